@@ -1,205 +1,506 @@
-use chrono::prelude::Local;
-use dlib_face_recognition::*;
-use image::*;
+use crate::backend::Rect;
+use crate::capture::{Capture, Source};
+use crate::config::{self, Config};
+#[cfg(any(test, feature = "mock"))]
+use crate::mock::MockSource;
+use crate::pipeline::{Pipeline, Settings};
+use crate::recognizer::{self, Recognizer};
+use crate::store::Store;
+use crate::{audit, snapshot};
+use image::RgbImage;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::{
     error::Error,
-    fs::{create_dir_all, read_to_string, File},
-    path::Path,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
-use v4l::{buffer::Stream, io, prelude::*, Format, FourCC};
 
-// Config struct to deserialize config.toml
-#[derive(Deserialize, Debug, Clone)]
-pub struct Config {
-    pub core: Core,
-    pub video: Video,
+// Downscaled copy of the last processed frame, kept for snapshots
+pub struct Frame {
+    pub image: RgbImage,
+    pub faces: Vec<Rect>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Core {
-    pub detection_notice: bool,
-    pub no_confirmation: bool,
-    pub suppress_unknown: bool,
-    pub suppress_timeout: bool,
-    pub ignore_ssh: bool,
-    pub ignore_closed_lid: bool,
-    pub disabled: bool,
-    pub use_cnn: bool,
-}
-#[derive(Deserialize, Debug, Clone)]
-pub struct Video {
-    pub certainty: f64,
-    pub timeout: u64,
-    pub device: usize,
-    pub max_height: u32,
-}
-
-// Model stuct for user face encoding
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct Model {
-    data: Vec<f64>,
-    pub label: String,
-    pub id: usize,
-    pub time: i64,
+// Outcome of a detection loop
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ScanResult {
+    pub identified: bool,
+    // Short code describing why the scan ended
+    pub reason: String,
+    pub frames: u32,
+    // Best distance seen to any of the user's models
+    pub distance: Option<f64>,
+    // Label of the model the face was identified as
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
-// Main app structure
-pub struct App<'a> {
-    // Dlib
-    cnn_detector: FaceDetectorCnn,
-    detector: FaceDetector,
-    encoder: FaceEncoderNetwork,
-    landmarks: LandmarkPredictor,
-
-    // Video capture
-    stream: Option<io::mmap::Stream<'a>>,
-    fmt: Option<Format>,
+// Ties the config, the user's stored models, video capture and the recognizer together
+pub struct App {
+    // Loaded on demand, commands that only touch the stored models never need it
+    pipeline: Option<Pipeline>,
+    // Worker count and whether the dlib CNN detector was wanted when it was loaded
+    loaded: (usize, bool),
+    // Used instead of the configured backend and the camera when set
+    backend: Option<fn() -> Recognizer>,
+    #[cfg(any(test, feature = "mock"))]
+    frames: Option<Vec<RgbImage>>,
+    last_frame: Option<Frame>,
 
     config: Config,
-    models: Vec<Model>,
+    store: Store,
+    // Name and dimension of the encoder of the configured backend, looked up on first use
+    // as it means hashing the dlib network
+    encoder: Option<(String, usize)>,
     user: String,
-    base_path: String,
+    base_path: PathBuf,
 }
 
-impl App<'_> {
-    pub fn new<P: AsRef<Path>, T: Into<String> + std::fmt::Display>(base_path: P, user: T) -> Self {
-        let dlib_model_path = base_path.as_ref().join("dlib_models");
-        let config_file_path = base_path.as_ref().join("config.toml");
-        let content = read_to_string(&config_file_path).expect("Failed to open config file");
-        let config: Config = toml::from_str(&content).expect("Failed to parse config file");
-        let detector = FaceDetector::new();
-        let cnn_detector =
-            FaceDetectorCnn::new(dlib_model_path.join("mmod_human_face_detector.dat")).unwrap();
-        let landmarks =
-            LandmarkPredictor::new(dlib_model_path.join("shape_predictor_5_face_landmarks.dat"))
-                .unwrap();
-        let encoder = FaceEncoderNetwork::new(
-            dlib_model_path.join("dlib_face_recognition_resnet_model_v1.dat"),
-        )
-        .unwrap();
-        let model_path = base_path.as_ref().join("models");
-        create_dir_all(&model_path).expect("Error creating models folder");
-        let json_file_path = model_path.join(&format!("{}.dat", user));
-        let models: Vec<Model> = match File::open(&json_file_path) {
-            Ok(f) => serde_json::from_reader(f).expect("s"),
-            Err(_) => {
-                let models: Vec<Model> = Vec::new();
-                let file = File::create(json_file_path).expect("Error creating initial model file");
-                serde_json::to_writer(&file, &models).expect("Error writing into initial model");
-                models
-            }
-        };
-        Self {
-            detector,
-            cnn_detector,
-            encoder,
-            landmarks,
-            stream: None,
-            fmt: None,
+impl App {
+    pub fn new<P: AsRef<Path>, T: Into<String> + std::fmt::Display>(
+        base_path: P,
+        user: T,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = config::load(&base_path)?;
+        Self::with_config(base_path, user, config)
+    }
+
+    // Like new, with a config that was already read and verified
+    pub fn with_config<P: AsRef<Path>, T: Into<String> + std::fmt::Display>(
+        base_path: P,
+        user: T,
+        config: Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let user = user.to_string();
+        let store = Store::open(&base_path, &user, config.storage.encrypt)?;
+        Ok(Self {
+            pipeline: None,
+            loaded: (0, false),
+            backend: None,
+            #[cfg(any(test, feature = "mock"))]
+            frames: None,
+            last_frame: None,
             config,
-            models,
-            user: user.to_string(),
-            base_path: base_path.as_ref().to_string_lossy().to_string(),
-        }
-    }
-
-    // Start video capture
-    pub fn start_capture(&mut self) {
-        let mut dev = CaptureDevice::new(self.config.video.device).expect("Failed to open device");
-        let mut fmt = dev.format().expect("Failed to read format");
-        fmt.fourcc = FourCC::new(b"RGB3");
-        dev.set_format(&fmt).expect("Failed to write format");
-        self.stream =
-            Some(MmapStream::with_buffers(&mut dev, 1).expect("Failed to create buffer stream"));
-        self.fmt = Some(fmt)
-    }
-
-    // Processes next frame available for face encodings
-    pub fn process_next_frame(&mut self) -> Option<Vec<FaceEncoding>> {
-        if self.stream.is_none() {
-            return None;
-        }
-        match self.stream.as_mut().unwrap().next() {
-            Ok(buffer) => {
-                let img: ImageBuffer<Rgb<u8>, _> = ImageBuffer::from_raw(
-                    self.fmt.unwrap().width,
-                    self.fmt.unwrap().height,
-                    buffer.data(),
-                )
-                .unwrap();
-                let width = (self.fmt.unwrap().width * self.config.video.max_height
-                    / self.fmt.unwrap().height) as usize;
-                let matrix = ImageMatrix::from_image(&img)
-                    .resize(width, self.config.video.max_height as usize);
-                let face_locations = match self.config.core.use_cnn {
-                    true => self.cnn_detector.face_locations(&matrix),
-                    false => self.detector.face_locations(&matrix),
-                };
-                let encodings: Vec<FaceEncoding> = face_locations
+            store,
+            encoder: None,
+            user,
+            base_path: base_path.as_ref().to_path_buf(),
+        })
+    }
+
+    // Creates recognizers with backend and plays back frames in a loop instead of loading the
+    // configured backend and capturing from the camera, e.g. with Recognizer::mock to run
+    // scans and enrollments without model files or hardware
+    #[cfg(any(test, feature = "mock"))]
+    pub fn with_backend<P: AsRef<Path>, T: Into<String> + std::fmt::Display>(
+        base_path: P,
+        user: T,
+        backend: fn() -> Recognizer,
+        frames: Vec<RgbImage>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = config::load(&base_path)?;
+        let mut app = Self::with_config(base_path, user, config)?;
+        app.backend = Some(backend);
+        app.frames = Some(frames);
+        Ok(app)
+    }
+
+    // Switches to another user, reloading the config and the user's models while keeping
+    // the backend loaded, unless the config now selects another one
+    pub fn switch_user(&mut self, user: &str) -> Result<(), Box<dyn Error>> {
+        let previous = std::mem::replace(&mut self.config, config::load(&self.base_path)?);
+        if self.backend.is_none()
+            && (previous.core.backend != self.config.core.backend
+                || previous.onnx != self.config.onnx)
+        {
+            self.pipeline = None;
+            self.encoder = None;
+        }
+        self.user = user.to_string();
+        self.last_frame = None;
+        self.store = Store::open(&self.base_path, user, self.config.storage.encrypt)?;
+        if let Some((encoder, dimension)) = self.encoder.as_ref() {
+            self.store.set_encoder(encoder, *dimension);
+        }
+        Ok(())
+    }
+
+    // Looks up the encoder of the configured backend and hands it to the store, which needs
+    // it to compare or add encodings
+    fn resolve_encoder(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.encoder.is_none() {
+            let encoder = match self.backend {
+                Some(backend) => {
+                    let recognizer = backend();
+                    (
+                        recognizer.encoder_name().to_string(),
+                        recognizer.encoder_dimension(),
+                    )
+                }
+                None => recognizer::encoder(&self.base_path, &self.config)?,
+            };
+            self.store.set_encoder(&encoder.0, encoder.1);
+            self.encoder = Some(encoder);
+        }
+        Ok(())
+    }
+
+    // Whether the stored models can be compared with encodings of the configured backend
+    pub fn compatible(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.resolve_encoder()?;
+        Ok(self.store.compatible())
+    }
+
+    // Starts the processing pipeline with a recognizer of the configured backend for each
+    // worker, if not running yet. It's restarted when the worker count changed or the dlib
+    // CNN detector is wanted but wasn't loaded.
+    pub fn load_models(&mut self) -> Result<(), Box<dyn Error>> {
+        let workers = self.config.video.workers.max(1);
+        let use_cnn = self.config.core.use_cnn && self.config.core.backend == "dlib";
+        if self.pipeline.is_some() && self.loaded.0 == workers && (self.loaded.1 || !use_cnn) {
+            return Ok(());
+        }
+        // Stop the previous workers before loading new models
+        self.pipeline = None;
+        let mut recognizers = Vec::new();
+        for _ in 0..workers {
+            recognizers.push(match self.backend {
+                Some(backend) => backend(),
+                None => Recognizer::load(&self.base_path, &self.config)?,
+            });
+        }
+        self.pipeline = Some(Pipeline::new(recognizers));
+        self.loaded = (workers, use_cnn);
+        Ok(())
+    }
+
+    // Start video capture, or playback of the frames given to with_backend. Needs the
+    // models to be loaded.
+    pub fn start_capture(&mut self) -> Result<(), Box<dyn Error>> {
+        self.start(self.config.video.tracking)
+    }
+
+    fn start(&mut self, track: bool) -> Result<(), Box<dyn Error>> {
+        let source = self.source()?;
+        let settings = Settings {
+            max_height: self.config.video.max_height,
+            use_cnn: self.config.core.use_cnn,
+            track,
+        };
+        self.pipeline
+            .as_mut()
+            .ok_or("The models are not loaded")?
+            .start(source, settings);
+        Ok(())
+    }
+
+    // The camera, or playback of the frames given to with_backend
+    fn source(&self) -> Result<Box<dyn Source>, Box<dyn Error>> {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(frames) = self.frames.as_ref() {
+            return Ok(Box::new(MockSource::new(frames.clone())));
+        }
+        Ok(Box::new(Capture::open(self.config.video.device)?))
+    }
+
+    // Stop video capture, releasing the device
+    pub fn stop_capture(&mut self) {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.stop();
+        }
+    }
+
+    pub fn store(&mut self) -> &mut Store {
+        &mut self.store
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Runs the detection loop until the user's face is identified, the timeout is reached
+    // or stop returns true. Learns from and snapshots the attempt as configured.
+    pub fn scan(&mut self, stop: &mut dyn FnMut() -> bool) -> ScanResult {
+        self.detect(stop, true)
+    }
+
+    // Like scan, but only checks the face against the models without learning from it
+    // or taking snapshots
+    pub fn verify(&mut self, stop: &mut dyn FnMut() -> bool) -> ScanResult {
+        self.detect(stop, false)
+    }
+
+    fn detect(&mut self, stop: &mut dyn FnMut() -> bool, record: bool) -> ScanResult {
+        let mut result = ScanResult::default();
+        if self.store.models().is_empty() {
+            result.reason = "no_models".to_string();
+            return result;
+        }
+        match self.compatible() {
+            Ok(true) => {}
+            Ok(false) => {
+                result.reason = "incompatible_models".to_string();
+                return result;
+            }
+            Err(err) => {
+                audit::log(&format!("failed to load models: {}", err));
+                result.reason = "models_unavailable".to_string();
+                return result;
+            }
+        }
+        if let Err(err) = self.load_models() {
+            audit::log(&format!("failed to load models: {}", err));
+            result.reason = "models_unavailable".to_string();
+            return result;
+        }
+        if let Err(err) = self.start_capture() {
+            audit::log(&format!("failed to start capture: {}", err));
+            result.reason = "camera_unavailable".to_string();
+            return result;
+        }
+        let timeout = Duration::from_secs(self.config.video.timeout);
+        let start_time = Instant::now();
+        result.reason = "timeout".to_string();
+        while start_time.elapsed() <= timeout {
+            if stop() {
+                result.reason = "aborted".to_string();
+                break;
+            }
+            if let Some(encodings) = self.process_next_frame() {
+                result.frames += 1;
+                let best = encodings
                     .iter()
-                    .map(|r| {
-                        let landmarks = self.landmarks.face_landmarks(&matrix, &r);
-                        self.encoder
-                            .get_face_encodings(&matrix, &[landmarks], 0)
-                            .first()
-                            .unwrap()
-                            .clone()
-                    })
-                    .collect();
-                if encodings.is_empty() {
-                    return None;
+                    .filter_map(|e| self.store.closest(e).map(|(d, m)| (e, d, m.label.clone())))
+                    .min_by(|x, y| x.1.total_cmp(&y.1));
+                if let Some((encoding, distance, label)) = best {
+                    if result.distance.map_or(true, |d| distance < d) {
+                        result.distance = Some(distance);
+                    }
+                    if distance >= self.config.video.certainty {
+                        continue;
+                    }
+
+                    // Learn from high-confidence matches when adaptive mode is on
+                    let adaptive = &self.config.adaptive;
+                    if record
+                        && adaptive.enabled
+                        && distance < adaptive.certainty
+                        && self.store.lock().is_ok()
+                    {
+                        self.store.learn(encoding.clone(), adaptive.max_samples);
+                        if let Err(err) = self.store.save() {
+                            audit::log(&format!("failed to save learned model: {}", err));
+                        }
+                        self.store.unlock();
+                    }
+
+                    result.identified = true;
+                    result.label = Some(label);
+                    result.reason = "identified".to_string();
+                    break;
                 }
-                Some(encodings)
             }
-            Err(_) => None,
+        }
+        self.stop_capture();
+        if record && result.reason != "aborted" {
+            self.take_snapshot(result.identified, result.distance);
+        }
+        result
+    }
+
+    // Saves the last frame when snapshots are enabled for the outcome
+    fn take_snapshot(&mut self, success: bool, distance: Option<f64>) {
+        let snapshots = &self.config.snapshots;
+        let wanted = match success {
+            true => snapshots.capture_successful,
+            false => snapshots.capture_failed,
+        };
+        if !wanted {
+            return;
+        }
+        if let Some(frame) = self.last_frame.as_ref() {
+            if let Err(err) = snapshot::save(
+                &self.base_path,
+                &self.user,
+                frame,
+                success,
+                distance,
+                snapshots.max_count,
+                self.config.storage.encrypt,
+            ) {
+                audit::log(&format!("failed to save snapshot: {}", err));
+            }
         }
     }
 
-    pub fn push_model(&mut self, model: Vec<f64>, label: String) {
-        self.models.push(Model {
-            data: model,
-            id: self.models.len(),
-            label,
-            time: Local::now().timestamp(),
-        });
+    // Waits for the next processed frame and returns the encodings of the faces in it, empty
+    // without faces. None means no frame arrived in time.
+    pub fn process_next_frame(&mut self) -> Option<Vec<Vec<f64>>> {
+        let detection = self.pipeline.as_ref()?.next()?;
+        let snapshots = &self.config.snapshots;
+        if snapshots.capture_failed || snapshots.capture_successful {
+            self.last_frame = Some(Frame {
+                image: detection.image,
+                faces: detection.faces,
+            });
+        }
+        Some(detection.encodings)
     }
 
-    pub fn find_model(&mut self, id: usize) -> Option<usize> {
-        self.models.iter().position(|x| x.id == id)
+    // Captures a single face and saves it as a new model, returning its ID
+    pub fn enroll(
+        &mut self,
+        label: &str,
+        stop: &mut dyn FnMut() -> bool,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<usize, Box<dyn Error>> {
+        if !self
+            .compatible()
+            .map_err(|e| format!("Error loading models: {}", e))?
+        {
+            return Err(
+                "Face models were created with a different encoder, clear them and re-enroll"
+                    .into(),
+            );
+        }
+        self.load_models()
+            .map_err(|e| format!("Error loading models: {}", e))?;
+        // Search whole frames, so a second person can't go unnoticed
+        self.start(false)
+            .map_err(|e| format!("Error opening camera: {}", e))?;
+        progress("Detecting face, please make sure you are in a well lit room, CTRL+C to exit");
+        let encoding = loop {
+            if stop() {
+                self.stop_capture();
+                return Err("Enrollment aborted".into());
+            }
+            if let Some(mut encodings) = self.process_next_frame() {
+                match encodings.len() {
+                    0 => {}
+                    1 => break encodings.remove(0),
+                    _ => progress("Found more than one person"),
+                }
+            }
+        };
+        self.stop_capture();
+
+        // Models may have changed while capturing, lock and reload them
+        self.store
+            .lock()
+            .map_err(|e| format!("Error locking the models: {}", e))?;
+        let id = self.store.push(encoding, label.to_string());
+        progress("Saving face encodings");
+        let saved: Result<(), Box<dyn Error>> = self
+            .store
+            .save()
+            .map_err(|e| format!("Error saving the models: {}", e).into());
+        self.store.unlock();
+        saved.map(|_| id)
     }
 
-    pub fn remove_model(&mut self, index: usize) {
-        self.models.remove(index);
+    pub fn identify(&mut self, encoding: &[f64]) -> bool {
+        match self.distance(encoding) {
+            Some(distance) => distance < self.config.video.certainty,
+            None => false,
+        }
     }
 
-    pub fn clear_models(&mut self) {
-        self.models = Vec::new();
+    // Smallest distance between the encoding and any of the user's models
+    pub fn distance(&mut self, encoding: &[f64]) -> Option<f64> {
+        self.store.closest(encoding).map(|(d, _)| d)
     }
+}
 
-    pub fn save_model(&mut self) -> Result<(), Box<dyn Error>> {
-        let json_file_path = Path::new(&self.base_path)
-            .join("models")
-            .join(&format!("{}.dat", self.user));
-        let file = File::create(json_file_path)?;
-        serde_json::to_writer(&file, &self.models)?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::test_base;
+    use crate::mock::face_frame;
+    use std::fs::remove_dir_all;
+
+    fn app(base_path: &Path, colour: [u8; 3]) -> App {
+        App::with_backend(
+            base_path,
+            "alice",
+            Recognizer::mock,
+            vec![face_frame(colour)],
+        )
+        .unwrap()
     }
 
-    pub fn models(&mut self) -> Vec<Model> {
-        self.models.clone()
+    #[test]
+    fn enrolls_and_identifies() {
+        let base_path = test_base("app-identify");
+        let id = app(&base_path, [200, 150, 100])
+            .enroll("front", &mut || false, &mut |_| {})
+            .unwrap();
+
+        let result = app(&base_path, [200, 150, 100]).scan(&mut || false);
+        remove_dir_all(&base_path).ok();
+        assert_eq!(id, 0);
+        assert!(result.identified);
+        assert_eq!(result.reason, "identified");
+        assert_eq!(result.label.as_deref(), Some("front"));
+        assert!(result.distance.unwrap() < 0.01);
     }
 
-    pub fn config(&mut self) -> Config {
-        self.config.clone()
+    #[test]
+    fn times_out_on_other_faces() {
+        let base_path = test_base("app-timeout");
+        app(&base_path, [200, 150, 100])
+            .enroll("front", &mut || false, &mut |_| {})
+            .unwrap();
+
+        let result = app(&base_path, [40, 200, 250]).scan(&mut || false);
+        remove_dir_all(&base_path).ok();
+        assert!(!result.identified);
+        assert_eq!(result.reason, "timeout");
+        assert!(result.frames > 0);
+        assert!(result.distance.unwrap() > 0.6);
     }
 
-    pub fn identify(&mut self, encoding: FaceEncoding) -> bool {
-        self.models.iter().any(|x| {
-            let x = FaceEncoding::new_from_vec(x.data.clone());
-            let distance = encoding.distance(&x);
-            distance < self.config.video.certainty
-        })
+    #[test]
+    fn counts_frames_without_faces() {
+        let base_path = test_base("app-empty");
+        app(&base_path, [200, 150, 100])
+            .enroll("front", &mut || false, &mut |_| {})
+            .unwrap();
+
+        let result = app(&base_path, [0, 0, 0]).scan(&mut || false);
+        remove_dir_all(&base_path).ok();
+        assert_eq!(result.reason, "timeout");
+        assert!(result.frames > 0);
+        assert_eq!(result.distance, None);
+    }
+
+    #[test]
+    fn refuses_missing_and_incompatible_models() {
+        let base_path = test_base("app-models");
+        let result = app(&base_path, [200, 150, 100]).scan(&mut || false);
+        assert_eq!(result.reason, "no_models");
+
+        let mut store = Store::open(&base_path, "alice", false).unwrap();
+        store.set_encoder("other", 128);
+        store.push(vec![0.0; 128], "front".to_string());
+        store.save().unwrap();
+        let mut a = app(&base_path, [200, 150, 100]);
+        let result = a.scan(&mut || false);
+        let enrolled = a.enroll("side", &mut || false, &mut |_| {});
+        remove_dir_all(&base_path).ok();
+        assert_eq!(result.reason, "incompatible_models");
+        assert!(enrolled.is_err());
+    }
+
+    #[test]
+    fn stops_when_asked() {
+        let base_path = test_base("app-stop");
+        let mut a = app(&base_path, [200, 150, 100]);
+        assert!(a.enroll("front", &mut || true, &mut |_| {}).is_err());
+        a.enroll("front", &mut || false, &mut |_| {}).unwrap();
+
+        let result = app(&base_path, [40, 200, 250]).scan(&mut || true);
+        remove_dir_all(&base_path).ok();
+        assert_eq!(result.reason, "aborted");
+        assert!(!result.identified);
     }
 }
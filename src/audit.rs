@@ -0,0 +1,214 @@
+use crate::helper::lock_file;
+use chrono::prelude::Local;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    ffi::CString,
+    fs::{read_to_string, rename, DirBuilder, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Writes a message to the authpriv syslog facility, which ends up in the journal
+pub fn log(message: &str) {
+    let message = match CString::new(format!("pam_hola: {}", message)) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    unsafe {
+        libc::syslog(
+            libc::LOG_AUTHPRIV | libc::LOG_WARNING,
+            b"%s\0".as_ptr() as *const libc::c_char,
+            message.as_ptr(),
+        );
+    }
+}
+
+// A single authentication attempt, logged once it's finished
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Attempt {
+    pub time: i64,
+    pub user: String,
+    pub service: String,
+    pub tty: String,
+    pub rhost: String,
+    pub success: bool,
+    // Short code describing why the attempt ended
+    pub reason: String,
+    pub elapsed_ms: u64,
+    pub frames: u32,
+    pub best_distance: Option<f64>,
+}
+
+impl Attempt {
+    pub fn new(user: &str, service: String, tty: String, rhost: String) -> Self {
+        Self {
+            time: Local::now().timestamp(),
+            user: user.to_string(),
+            service,
+            tty,
+            rhost,
+            success: false,
+            reason: String::new(),
+            elapsed_ms: 0,
+            frames: 0,
+            best_distance: None,
+        }
+    }
+
+    pub fn finish(&mut self, success: bool, elapsed: Duration) {
+        self.success = success;
+        self.elapsed_ms = elapsed.as_millis() as u64;
+    }
+
+    pub fn result(&self) -> &str {
+        match self.success {
+            true => "success",
+            false => "failure",
+        }
+    }
+}
+
+// Size at which the attempt log is rotated, keeping only the previous file
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+pub fn log_file_path<P: AsRef<Path>>(base_path: P) -> PathBuf {
+    base_path.as_ref().join("logs").join("attempts.jsonl")
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    path.with_extension("jsonl.1")
+}
+
+// Logs an attempt to syslog, and appends it to the JSON lines file when enabled
+pub fn record<P: AsRef<Path>>(base_path: P, attempt: &Attempt, json: bool) {
+    log(&summary(attempt));
+    if json {
+        if let Err(err) = append(base_path, attempt, MAX_LOG_SIZE) {
+            log(&format!("failed to write attempt log: {}", err));
+        }
+    }
+}
+
+// The attempt as key=value pairs for syslog
+fn summary(attempt: &Attempt) -> String {
+    // Values from PAM items are quoted, so they can't add fields of their own
+    format!(
+        "user={:?} service={:?} tty={:?} rhost={:?} result={} reason={} elapsed_ms={} frames={} \
+         best_distance={}",
+        attempt.user,
+        attempt.service,
+        attempt.tty,
+        attempt.rhost,
+        attempt.result(),
+        attempt.reason,
+        attempt.elapsed_ms,
+        attempt.frames,
+        attempt
+            .best_distance
+            .map_or("none".to_string(), |d| format!("{:.4}", d)),
+    )
+}
+
+// Appends an attempt, first rotating the log once it reached max_size
+fn append<P: AsRef<Path>>(
+    base_path: P,
+    attempt: &Attempt,
+    max_size: u64,
+) -> Result<(), Box<dyn Error>> {
+    let path = log_file_path(base_path);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path.parent().unwrap())?;
+    let _lock = lock_file(&path)?;
+    if path.metadata().map_or(false, |m| m.len() >= max_size) {
+        rename(&path, rotated_path(&path))?;
+    }
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(&path)?;
+    let mut line = serde_json::to_vec(attempt)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+// Reads logged attempts including the rotated log, oldest first, skipping lines that can't
+// be parsed
+pub fn read_attempts<P: AsRef<Path>>(base_path: P) -> Result<Vec<Attempt>, Box<dyn Error>> {
+    let path = log_file_path(base_path);
+    let content = read_to_string(&path)?;
+    let rotated = read_to_string(rotated_path(&path)).unwrap_or_default();
+    Ok(rotated
+        .lines()
+        .chain(content.lines())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::test_base;
+    use std::fs::remove_dir_all;
+
+    fn attempt(user: &str, reason: &str) -> Attempt {
+        let mut attempt =
+            Attempt::new(user, "sudo".to_string(), "pts/0".to_string(), String::new());
+        attempt.reason = reason.to_string();
+        attempt.finish(false, Duration::from_millis(1500));
+        attempt
+    }
+
+    #[test]
+    fn reads_appended_attempts() {
+        let base_path = test_base("audit-append");
+        record(&base_path, &attempt("alice", "timeout"), true);
+        let mut identified = attempt("alice", "identified");
+        identified.finish(true, Duration::from_millis(200));
+        identified.best_distance = Some(0.3);
+        record(&base_path, &identified, true);
+        // Only syslog without json
+        record(&base_path, &attempt("bob", "timeout"), false);
+
+        let attempts = read_attempts(&base_path).unwrap();
+        remove_dir_all(&base_path).ok();
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].reason, "timeout");
+        assert_eq!(attempts[0].elapsed_ms, 1500);
+        assert!(attempts[1].success);
+        assert_eq!(attempts[1].best_distance, Some(0.3));
+    }
+
+    #[test]
+    fn rotates_large_logs() {
+        let base_path = test_base("audit-rotate");
+        for i in 0..8 {
+            append(&base_path, &attempt("alice", &i.to_string()), 400).unwrap();
+        }
+        let rotated = rotated_path(&log_file_path(&base_path)).exists();
+        let attempts = read_attempts(&base_path).unwrap();
+        remove_dir_all(&base_path).ok();
+        assert!(rotated);
+        // The oldest attempts are dropped, the rest stay in order
+        let reasons: Vec<&str> = attempts.iter().map(|x| x.reason.as_str()).collect();
+        assert!(!reasons.contains(&"0"));
+        assert_eq!(reasons.last(), Some(&"7"));
+        assert!(reasons.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn quotes_pam_items() {
+        let mut attempt = attempt("alice", "timeout");
+        attempt.rhost = "host result=success\nuser=root".to_string();
+        let summary = summary(&attempt);
+        assert!(summary.starts_with("user=\"alice\" service=\"sudo\" tty=\"pts/0\""));
+        assert!(summary.contains("rhost=\"host result=success\\nuser=root\" result=failure"));
+        assert!(summary.ends_with("best_distance=none"));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::app::ScanResult;
+use crate::audit::{self, Attempt};
+use crate::config::Config;
+use crate::state::StateFile;
+use std::{
+    fs::{read_dir, read_to_string},
+    path::Path,
+    time::Instant,
+};
+
+// How an authentication attempt ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Identified,
+    // The face wasn't identified before the timeout, counted towards the lockout
+    Failed,
+    // Face login can't be used right now, the password has to be used instead
+    Unavailable,
+    // The user has no face models
+    UnknownUser,
+}
+
+// Where messages for the user go, the PAM conversation or nowhere for D-Bus clients
+pub trait Messages {
+    fn info(&mut self, message: &str);
+    fn error(&mut self, message: &str);
+}
+
+// Drops all messages
+pub struct Silent;
+
+impl Messages for Silent {
+    fn info(&mut self, _message: &str) {}
+    fn error(&mut self, _message: &str) {}
+}
+
+// Whether any lid reports being closed, there's usually a single one called LID0
+fn lid_closed() -> bool {
+    read_dir("/proc/acpi/button/lid").map_or(false, |entries| {
+        entries
+            .filter_map(|e| e.ok())
+            .any(|e| read_to_string(e.path().join("state")).map_or(false, |s| s.contains("closed")))
+    })
+}
+
+// Decides whether the user may log in with their face under the configured policy, the
+// lockout and required passwords, and records the outcome in the user's state. scan looks
+// for the face and is only called when face login is allowed. The attempt gets the reason,
+// frames and best distance.
+pub fn authenticate(
+    base_path: &Path,
+    user: &str,
+    config: &Config,
+    attempt: &mut Attempt,
+    messages: &mut dyn Messages,
+    scan: &mut dyn FnMut() -> Result<ScanResult, String>,
+) -> Outcome {
+    // Abort is Hola is disabled
+    if config.core.disabled {
+        attempt.reason = "disabled".to_string();
+        return Outcome::Unavailable;
+    }
+
+    // Abort if we're in a remote SSH env
+    if config.core.ignore_ssh {
+        let keys = vec!["SSH_CONNECTION", "SSH_CLIENT", "SSHD_OPTS"];
+        if keys.iter().any(|k| std::env::var(k).is_ok()) {
+            attempt.reason = "remote_session".to_string();
+            return Outcome::Unavailable;
+        }
+    }
+
+    // Abort if lid is closed
+    if config.core.ignore_closed_lid && lid_closed() {
+        attempt.reason = "lid_closed".to_string();
+        return Outcome::Unavailable;
+    }
+
+    // Abort while the user is locked out after too many failed attempts
+    match StateFile::open(base_path, user) {
+        Ok(f) => {
+            if let Some(left) = f.state.locked_for() {
+                audit::log(&format!("{} is locked out for {} seconds", user, left));
+                if !config.core.suppress_timeout {
+                    messages.error("Too many failed face detections, face login is locked");
+                }
+                attempt.reason = "locked_out".to_string();
+                return Outcome::Unavailable;
+            }
+
+            // Require the password again after enough face logins or time
+            let core = &config.core;
+            if f.state
+                .password_required(core.max_face_logins, core.password_interval)
+            {
+                if !core.suppress_timeout {
+                    messages.info("Password required");
+                }
+                attempt.reason = "password_required".to_string();
+                return Outcome::Unavailable;
+            }
+        }
+        Err(err) => {
+            audit::log(&format!("failed to read state of {}: {}", user, err));
+            attempt.reason = "state_unavailable".to_string();
+            return Outcome::Unavailable;
+        }
+    }
+
+    // Alert the user that we are doing face detection
+    if config.core.detection_notice {
+        messages.info("Attempting face detection");
+    }
+
+    let start_time = Instant::now();
+    let result = match scan() {
+        Ok(result) => result,
+        Err(err) => {
+            audit::log(&format!("refusing to authenticate {}: {}", user, err));
+            attempt.reason = "init_failed".to_string();
+            return Outcome::Unavailable;
+        }
+    };
+    attempt.frames = result.frames;
+    attempt.best_distance = result.distance;
+    attempt.reason = result.reason.clone();
+
+    if result.identified {
+        if !config.core.no_confirmation {
+            messages.info(&format!(
+                "Identified face as {} in {:?}",
+                user,
+                start_time.elapsed()
+            ));
+        }
+        if let Ok(mut f) = StateFile::open(base_path, user) {
+            f.state.reset_lockout();
+            f.state.record_face_login();
+            f.save().ok();
+        }
+        return Outcome::Identified;
+    }
+
+    match result.reason.as_str() {
+        // Couldn't find any face model for the user
+        "no_models" => {
+            if !config.core.suppress_unknown {
+                messages.error("No face model known");
+            }
+            Outcome::UnknownUser
+        }
+
+        // Refuse models produced by a different face encoder network
+        "incompatible_models" => {
+            if !config.core.suppress_unknown {
+                messages
+                    .error("Face models were created with a different encoder, please re-enroll");
+            }
+            Outcome::Unavailable
+        }
+
+        // Timeout reached
+        "timeout" => {
+            let lockout = &config.lockout;
+            if let Ok(mut f) = StateFile::open(base_path, user) {
+                f.state
+                    .record_failure(lockout.max_failures, lockout.window, lockout.duration);
+                f.save().ok();
+            }
+            if !config.core.suppress_timeout {
+                messages.error("Face detection timeout reached");
+            }
+            Outcome::Failed
+        }
+
+        // Aborted by the user or the camera and models couldn't be used
+        _ => Outcome::Unavailable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::helper::test_base;
+    use std::fs::{read, remove_dir_all};
+
+    // Keeps the messages shown to the user
+    #[derive(Default)]
+    struct Recorded(Vec<String>);
+
+    impl Messages for Recorded {
+        fn info(&mut self, message: &str) {
+            self.0.push(message.to_string());
+        }
+
+        fn error(&mut self, message: &str) {
+            self.0.push(message.to_string());
+        }
+    }
+
+    fn result(identified: bool, reason: &str) -> ScanResult {
+        ScanResult {
+            identified,
+            reason: reason.to_string(),
+            frames: 3,
+            distance: Some(0.5),
+            label: None,
+        }
+    }
+
+    // Runs an attempt with a scan ending as given, returning the outcome, the attempt's
+    // reason, whether the scan ran and the messages shown
+    fn run(
+        base_path: &Path,
+        config: &Config,
+        scanned: ScanResult,
+    ) -> (Outcome, String, bool, Vec<String>) {
+        let mut attempt = Attempt::new("alice", String::new(), String::new(), String::new());
+        let mut messages = Recorded::default();
+        let mut called = false;
+        let mut scan = || {
+            called = true;
+            Ok(scanned.clone())
+        };
+        let outcome = authenticate(
+            base_path,
+            "alice",
+            config,
+            &mut attempt,
+            &mut messages,
+            &mut scan,
+        );
+        (outcome, attempt.reason, called, messages.0)
+    }
+
+    fn load(base_path: &Path) -> Config {
+        config::parse(&read(base_path.join("config.toml")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn identifies_and_counts_face_logins() {
+        let base_path = test_base("auth-identify");
+        let mut config = load(&base_path);
+        config.core.max_face_logins = 1;
+
+        let (outcome, reason, called, messages) =
+            run(&base_path, &config, result(true, "identified"));
+        assert_eq!(outcome, Outcome::Identified);
+        assert_eq!(reason, "identified");
+        assert!(called);
+        assert!(messages[0].starts_with("Identified face as alice"));
+
+        // The password is required again before the next scan
+        let (outcome, reason, called, _) = run(&base_path, &config, result(true, "identified"));
+        remove_dir_all(&base_path).ok();
+        assert_eq!(outcome, Outcome::Unavailable);
+        assert_eq!(reason, "password_required");
+        assert!(!called);
+    }
+
+    #[test]
+    fn locks_out_after_timeouts() {
+        let base_path = test_base("auth-lockout");
+        let mut config = load(&base_path);
+        config.lockout.max_failures = 2;
+
+        for _ in 0..2 {
+            let (outcome, reason, called, messages) =
+                run(&base_path, &config, result(false, "timeout"));
+            assert_eq!(outcome, Outcome::Failed);
+            assert_eq!(reason, "timeout");
+            assert!(called);
+            assert_eq!(messages, vec!["Face detection timeout reached"]);
+        }
+        let (outcome, reason, called, _) = run(&base_path, &config, result(true, "identified"));
+        assert_eq!(outcome, Outcome::Unavailable);
+        assert_eq!(reason, "locked_out");
+        assert!(!called);
+
+        // Face login works again once the lockout is lifted
+        let mut file = StateFile::open(&base_path, "alice").unwrap();
+        file.state.reset_lockout();
+        file.save().unwrap();
+        drop(file);
+        let (outcome, _, _, _) = run(&base_path, &config, result(true, "identified"));
+        remove_dir_all(&base_path).ok();
+        assert_eq!(outcome, Outcome::Identified);
+    }
+
+    #[test]
+    fn maps_scan_failures() {
+        let base_path = test_base("auth-failures");
+        let mut config = load(&base_path);
+
+        let (outcome, _, _, messages) = run(&base_path, &config, result(false, "no_models"));
+        assert_eq!(outcome, Outcome::UnknownUser);
+        assert_eq!(messages, vec!["No face model known"]);
+
+        let (outcome, _, _, _) = run(&base_path, &config, result(false, "incompatible_models"));
+        assert_eq!(outcome, Outcome::Unavailable);
+
+        let (outcome, reason, _, messages) = run(&base_path, &config, result(false, "aborted"));
+        assert_eq!(outcome, Outcome::Unavailable);
+        assert_eq!(reason, "aborted");
+        assert!(messages.is_empty());
+
+        // Neither counts towards the lockout
+        let file = StateFile::open(&base_path, "alice").unwrap();
+        assert!(file.state.failures.is_empty());
+        drop(file);
+
+        config.core.disabled = true;
+        let (outcome, reason, called, _) = run(&base_path, &config, result(true, "identified"));
+        remove_dir_all(&base_path).ok();
+        assert_eq!(outcome, Outcome::Unavailable);
+        assert_eq!(reason, "disabled");
+        assert!(!called);
+    }
+}
@@ -0,0 +1,35 @@
+use image::RgbImage;
+use std::any::Any;
+
+// Face box as left, top, right and bottom in image coordinates
+pub type Rect = (i64, i64, i64, i64);
+
+// Landmarks of a single face. Backends whose encoder needs its own landmark representation
+// keep it in native, next to the plain points.
+pub struct Landmarks {
+    pub face: Rect,
+    pub points: Vec<(i64, i64)>,
+    pub native: Option<Box<dyn Any>>,
+}
+
+// Finds faces in a frame
+pub trait Detector: Send {
+    fn detect(&self, image: &RgbImage) -> Vec<Rect>;
+}
+
+// Locates the landmarks of each face found by a detector
+pub trait LandmarkModel: Send {
+    fn landmarks(&self, image: &RgbImage, faces: &[Rect]) -> Vec<Landmarks>;
+}
+
+// Turns faces into encodings that can be compared by distance
+pub trait Encoder: Send {
+    // Identifies the network, stored with the models so encodings of different encoders
+    // are never compared
+    fn name(&self) -> &str;
+
+    // Length of the encodings
+    fn dimension(&self) -> usize;
+
+    fn encode(&self, image: &RgbImage, landmarks: &[Landmarks]) -> Vec<Vec<f64>>;
+}
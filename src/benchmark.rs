@@ -0,0 +1,97 @@
+use crate::capture::Source;
+use crate::config::Config;
+use crate::recognizer::Recognizer;
+use std::{
+    error::Error,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// Frames processed before measuring, so one-time setup like allocating buffers isn't counted
+const WARMUP_FRAMES: usize = 2;
+
+// Detectors of the configured backend, by name and whether it's the dlib CNN detector
+pub fn detectors(config: &Config) -> Vec<(&'static str, bool)> {
+    match config.core.backend.as_str() {
+        "dlib" => vec![("hog", false), ("cnn", true)],
+        _ => vec![("scrfd", false)],
+    }
+}
+
+// Loads a recognizer of the configured backend for the detector
+pub fn load<P: AsRef<Path>>(
+    base_path: P,
+    config: &Config,
+    use_cnn: bool,
+) -> Result<Recognizer, Box<dyn Error>> {
+    let mut recognizer = Recognizer::load(&base_path, config)?;
+    if use_cnn {
+        recognizer.load_cnn(&base_path)?;
+    }
+    Ok(recognizer)
+}
+
+// Latencies of each stage over all measured frames
+#[derive(Default)]
+pub struct Stats {
+    pub capture: Vec<Duration>,
+    pub resize: Vec<Duration>,
+    pub detection: Vec<Duration>,
+    pub landmarks: Vec<Duration>,
+    pub encoding: Vec<Duration>,
+    // Frames a face was found in
+    pub faces: usize,
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    pub fn frames(&self) -> usize {
+        self.capture.len()
+    }
+
+    // Frames captured and processed per second, one after the other
+    pub fn fps(&self) -> f64 {
+        self.frames() as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+// Latency below which the given fraction of samples falls
+pub fn percentile(samples: &[Duration], fraction: f64) -> Duration {
+    if samples.is_empty() {
+        return Duration::default();
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+// Captures and processes frames one at a time on the calling thread, timing every stage
+pub fn run(
+    recognizer: &Recognizer,
+    source: &mut dyn Source,
+    frames: usize,
+    max_height: u32,
+    use_cnn: bool,
+) -> Result<Stats, Box<dyn Error>> {
+    for _ in 0..WARMUP_FRAMES {
+        recognizer.process(&source.next_frame()?, max_height, use_cnn, None);
+    }
+    let mut stats = Stats::default();
+    let start = Instant::now();
+    for _ in 0..frames {
+        let captured = Instant::now();
+        let image = source.next_frame()?;
+        stats.capture.push(captured.elapsed());
+        let (detection, stages) = recognizer.process_timed(&image, max_height, use_cnn, None);
+        stats.resize.push(stages.resize);
+        stats.detection.push(stages.detection);
+        stats.landmarks.push(stages.landmarks);
+        stats.encoding.push(stages.encoding);
+        if !detection.faces.is_empty() {
+            stats.faces += 1;
+        }
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
@@ -0,0 +1,87 @@
+use crate::daemon::{self, Request, Response};
+use crate::helper::user_name;
+use zbus::{fdo, Connection, MessageHeader};
+
+// Unique bus name of the client that sent a message
+pub fn sender(header: &MessageHeader) -> Result<String, String> {
+    match header.sender() {
+        Ok(Some(sender)) => Ok(sender.to_string()),
+        _ => Err("Message has no sender".to_string()),
+    }
+}
+
+// Uid and login name of the client that sent a message, as known to the bus
+pub fn caller(connection: &Connection, header: &MessageHeader) -> Result<(u32, String), String> {
+    let sender = sender(header)?;
+    let uid = fdo::DBusProxy::new(connection)
+        .and_then(|p| p.get_connection_unix_user(&sender))
+        .map_err(|e| e.to_string())?;
+    let name = user_name(uid).ok_or_else(|| format!("Unknown user ID {}", uid))?;
+    Ok((uid, name))
+}
+
+// Why a caller couldn't be authorized
+pub enum AuthError {
+    Internal(String),
+    PermissionDenied(String),
+}
+
+// Resolves the user a call is about, empty meaning the caller. Root may act on any user,
+// other callers only on themselves.
+pub fn authorize(
+    connection: &Connection,
+    header: &MessageHeader,
+    user: &str,
+) -> Result<String, AuthError> {
+    let (uid, caller) = caller(connection, header).map_err(AuthError::Internal)?;
+    match user {
+        "" => Ok(caller),
+        user if uid == 0 || user == caller => Ok(user.to_string()),
+        _ => Err(AuthError::PermissionDenied(format!(
+            "Not allowed to access the face models of user {}",
+            user
+        ))),
+    }
+}
+
+// Only lets root through
+pub fn authorize_root(connection: &Connection, header: &MessageHeader) -> Result<(), AuthError> {
+    match caller(connection, header).map_err(AuthError::Internal)? {
+        (0, _) => Ok(()),
+        _ => Err(AuthError::PermissionDenied(
+            "Only root is allowed to do this".to_string(),
+        )),
+    }
+}
+
+// Runs requests for the D-Bus services, holad unless a test provides another backend
+pub trait Backend: Send + Sync {
+    // Returns None when stop returned true before the request was done
+    fn run(&self, request: &Request, stop: &dyn Fn() -> bool) -> Result<Option<Response>, String>;
+}
+
+// Sends requests to holad
+pub struct Holad;
+
+impl Backend for Holad {
+    fn run(&self, request: &Request, stop: &dyn Fn() -> bool) -> Result<Option<Response>, String> {
+        match daemon::request_until(request, stop) {
+            Ok(Some(Response::Error(err))) => Err(err),
+            Ok(response) => Ok(response),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+// Runs a request to completion, turning failures into the service's D-Bus error
+pub fn request<E>(
+    backend: &dyn Backend,
+    request: Request,
+    error: fn(String) -> E,
+) -> Result<Response, E> {
+    match backend.run(&request, &|| false) {
+        Ok(Some(response)) => Ok(response),
+        Ok(None) => Err(error("Request was stopped".to_string())),
+        Err(err) => Err(error(err)),
+    }
+}
@@ -0,0 +1,89 @@
+use image::{ImageBuffer, RgbImage};
+use std::{
+    error::Error,
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+use v4l::{buffer::Stream, io, prelude::*, Format, FourCC};
+
+// Capture buffers queued with the driver, so the camera keeps streaming while a frame is
+// copied out
+const BUFFERS: u32 = 4;
+
+// Supplies the frames to process
+pub trait Source: Send {
+    fn next_frame(&mut self) -> Result<RgbImage, Box<dyn Error>>;
+}
+
+// RGB video capture from a V4L device
+pub struct Capture<'a> {
+    stream: io::mmap::Stream<'a>,
+    fmt: Format,
+}
+
+impl Capture<'_> {
+    pub fn open(device: usize) -> Result<Self, Box<dyn Error>> {
+        let mut dev =
+            CaptureDevice::new(device).map_err(|e| format!("Failed to open device: {}", e))?;
+        let mut fmt = dev
+            .format()
+            .map_err(|e| format!("Failed to read format: {}", e))?;
+        fmt.fourcc = FourCC::new(b"RGB3");
+        dev.set_format(&fmt)
+            .map_err(|e| format!("Failed to write format: {}", e))?;
+        let stream = MmapStream::with_buffers(&mut dev, BUFFERS)
+            .map_err(|e| format!("Failed to create buffer stream: {}", e))?;
+        Ok(Self { stream, fmt })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.fmt.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.fmt.height
+    }
+}
+
+impl Source for Capture<'_> {
+    // Waits for the next frame and copies it out of the capture buffer
+    fn next_frame(&mut self) -> Result<RgbImage, Box<dyn Error>> {
+        let buffer = self.stream.next()?;
+        let image: RgbImage =
+            ImageBuffer::from_raw(self.fmt.width, self.fmt.height, buffer.data().to_vec())
+                .ok_or("Frame is smaller than the capture format")?;
+        Ok(image)
+    }
+}
+
+// Plays back the images of a directory in name order, looping at the end. Each frame is
+// decoded when it's requested, like a camera frame would be captured.
+pub struct Recording {
+    paths: Vec<PathBuf>,
+    next: usize,
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Box<dyn Error>> {
+        let mut paths: Vec<PathBuf> = read_dir(&dir)
+            .map_err(|e| format!("Failed to read {}: {}", dir.as_ref().display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| image::ImageFormat::from_path(path).is_ok())
+            .collect();
+        if paths.is_empty() {
+            return Err(format!("No images found in {}", dir.as_ref().display()).into());
+        }
+        paths.sort();
+        Ok(Self { paths, next: 0 })
+    }
+}
+
+impl Source for Recording {
+    fn next_frame(&mut self) -> Result<RgbImage, Box<dyn Error>> {
+        let path = &self.paths[self.next % self.paths.len()];
+        self.next += 1;
+        let image =
+            image::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Ok(image.to_rgb8())
+    }
+}
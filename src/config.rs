@@ -0,0 +1,175 @@
+use crate::crypto;
+use serde::Deserialize;
+use std::{error::Error, fs::read, path::Path};
+
+// Config struct to deserialize config.toml
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub core: Core,
+    pub video: Video,
+    #[serde(default)]
+    pub adaptive: Adaptive,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub lockout: Lockout,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub snapshots: Snapshots,
+    #[serde(default)]
+    pub onnx: Onnx,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Core {
+    pub detection_notice: bool,
+    pub no_confirmation: bool,
+    pub suppress_unknown: bool,
+    pub suppress_timeout: bool,
+    pub ignore_ssh: bool,
+    pub ignore_closed_lid: bool,
+    pub disabled: bool,
+    pub use_cnn: bool,
+    #[serde(default)]
+    pub max_face_logins: u32,
+    #[serde(default)]
+    pub password_interval: u64,
+    #[serde(default)]
+    pub concurrent_password: bool,
+    // Face detection and recognition backend, dlib or onnx
+    #[serde(default = "default_backend")]
+    pub backend: String,
+}
+
+// dlib when built with it, the ONNX models otherwise
+fn default_backend() -> String {
+    match cfg!(feature = "dlib") {
+        true => "dlib".to_string(),
+        false => "onnx".to_string(),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Video {
+    pub certainty: f64,
+    pub timeout: u64,
+    pub device: usize,
+    pub max_height: u32,
+    // Only search around the face found in the previous frame
+    #[serde(default = "default_tracking")]
+    pub tracking: bool,
+    // Threads processing frames, each loads its own copy of the models
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_tracking() -> bool {
+    true
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct Adaptive {
+    pub enabled: bool,
+    pub certainty: f64,
+    pub max_samples: usize,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            certainty: 0.4,
+            max_samples: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Storage {
+    pub encrypt: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Lockout {
+    pub max_failures: usize,
+    pub window: i64,
+    pub duration: i64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: 900,
+            duration: 600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Log {
+    pub json: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Snapshots {
+    pub capture_failed: bool,
+    pub capture_successful: bool,
+    pub max_count: usize,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self {
+            capture_failed: false,
+            capture_successful: false,
+            max_count: 50,
+        }
+    }
+}
+
+// Model files of the onnx backend in onnx_models, and inference threads per model
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Onnx {
+    pub detector: String,
+    pub recognizer: String,
+    pub threads: i16,
+}
+
+impl Default for Onnx {
+    fn default() -> Self {
+        Self {
+            detector: "det_500m.onnx".to_string(),
+            recognizer: "w600k_mbf.onnx".to_string(),
+            threads: 1,
+        }
+    }
+}
+
+// Reads config.toml, verifying its signature when it has one
+pub fn load<P: AsRef<Path>>(base_path: P) -> Result<Config, Box<dyn Error>> {
+    let config_file_path = base_path.as_ref().join("config.toml");
+    let content =
+        read(&config_file_path).map_err(|e| format!("Failed to open config file: {}", e))?;
+    crypto::verify_content(
+        &base_path,
+        &config_file_path,
+        &content,
+        crypto::CONFIG_CONTEXT,
+        false,
+    )?;
+    parse(&content)
+}
+
+// Parses the content of a config file
+pub fn parse(content: &[u8]) -> Result<Config, Box<dyn Error>> {
+    let content =
+        std::str::from_utf8(content).map_err(|e| format!("Failed to parse config file: {}", e))?;
+    let config: Config =
+        toml::from_str(content).map_err(|e| format!("Failed to parse config file: {}", e))?;
+    Ok(config)
+}
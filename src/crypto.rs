@@ -0,0 +1,353 @@
+use crate::helper::{lock_file, write_atomic};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{
+    error::Error,
+    fs::{read, remove_file, symlink_metadata, DirBuilder, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+// Header marking an encrypted model file, followed by the nonce and the ciphertext
+const MAGIC: &[u8] = b"HOLAENC1";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+// Names of the key files, also used as the systemd credential names of holad
+pub const MODEL_KEY: &str = "models.key";
+pub const SIGNING_KEY: &str = "signing.key";
+
+// Context the config file is signed with, model files use the user name
+pub const CONFIG_CONTEXT: &str = "config.toml";
+
+// Directory of systemd credentials, only set by holad. The PAM module runs in processes
+// like su and sudo whose environment the caller controls, so it always uses the key files.
+static CREDENTIALS: OnceLock<PathBuf> = OnceLock::new();
+
+// Takes keys from the systemd credentials directory of the service, when it's set and only
+// root could have put files there
+pub fn use_credentials() -> Result<(), Box<dyn Error>> {
+    let dir = match std::env::var_os("CREDENTIALS_DIRECTORY") {
+        Some(dir) => PathBuf::from(dir),
+        None => return Ok(()),
+    };
+    if !dir.starts_with("/run/credentials") || !root_only(&dir) {
+        return Err(format!("Ignoring credentials directory {}", dir.display()).into());
+    }
+    CREDENTIALS.set(dir).ok();
+    Ok(())
+}
+
+// Whether a path is a root-owned file or directory others can't modify, without following
+// symlinks
+fn root_only(path: &Path) -> bool {
+    symlink_metadata(path).map_or(false, |m| {
+        !m.file_type().is_symlink() && m.uid() == 0 && m.mode() & 0o022 == 0
+    })
+}
+
+// Location of a key, a systemd credential takes precedence over the key file in holad
+pub fn key_path<P: AsRef<Path>>(base_path: P, name: &str) -> PathBuf {
+    if let Some(dir) = CREDENTIALS.get() {
+        let credential = dir.join(name);
+        if root_only(&credential) {
+            return credential;
+        }
+    }
+    base_path.as_ref().join("keys").join(name)
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = vec![0u8; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Loads a key, generating a root-only key file when allowed and missing
+pub fn load_key<P: AsRef<Path>>(
+    base_path: P,
+    name: &str,
+    create: bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let path = key_path(&base_path, name);
+    if !path.exists() {
+        if !create {
+            return Err(format!("Missing key {}", path.display()).into());
+        }
+        let key = random_bytes(KEY_LEN)?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(base_path.as_ref().join("keys"))?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(&key)?;
+        file.sync_all()?;
+        return Ok(key);
+    }
+    let key = read(&path)?;
+    if key.len() != KEY_LEN {
+        return Err(format!("Invalid key {}", path.display()).into());
+    }
+    Ok(key)
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Encrypts data bound to the given context, so files can't be swapped between users
+pub fn encrypt(key: &[u8], data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = random_bytes(NONCE_LEN)?;
+    let payload = Payload {
+        msg: data,
+        aad: context.as_bytes(),
+    };
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| "Failed to encrypt data")?;
+    let mut out = MAGIC.to_vec();
+    out.extend(nonce);
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+pub fn decrypt(key: &[u8], data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !is_encrypted(data) || data.len() < MAGIC.len() + NONCE_LEN {
+        return Err("Data is not encrypted".into());
+    }
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let (nonce, ciphertext) = data[MAGIC.len()..].split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: context.as_bytes(),
+    };
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| "Failed to decrypt data, wrong key or corrupted file")?;
+    Ok(plaintext)
+}
+
+// Encrypts every plaintext model file in place, returning the users that were migrated.
+// Plaintext backups are removed, they would keep the models readable.
+pub fn encrypt_model_files<P: AsRef<Path>>(base_path: P) -> Result<Vec<String>, Box<dyn Error>> {
+    let key = load_key(&base_path, MODEL_KEY, true)?;
+    let mut users = Vec::new();
+    for entry in std::fs::read_dir(base_path.as_ref().join("models"))? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |x| x != "dat") {
+            continue;
+        }
+        let _lock = lock_file(&path)?;
+        let backup = path.with_extension("bak");
+        if read(&backup).map_or(false, |x| !is_encrypted(&x)) {
+            remove_file(&backup)?;
+        }
+        let content = read(&path)?;
+        if is_encrypted(&content) {
+            continue;
+        }
+        let user = path.file_stem().unwrap().to_string_lossy().to_string();
+        let encrypted = encrypt(&key, &content, &user)?;
+        write_atomic(&path, &encrypted)?;
+        if signing_enabled(&base_path) {
+            sign_file(&base_path, &path, &user)?;
+        }
+        users.push(user);
+    }
+    Ok(users)
+}
+
+// Signing is enabled once a signing key has been generated with `hola sign`
+pub fn signing_enabled<P: AsRef<Path>>(base_path: P) -> bool {
+    key_path(base_path, SIGNING_KEY).exists()
+}
+
+// Detached signatures are stored next to the signed file
+pub fn signature_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    PathBuf::from(format!("{}.sig", path.as_ref().display()))
+}
+
+// HMAC over the file content bound to the given context, so signed files can't be swapped
+fn mac(key: &[u8], data: &[u8], context: &str) -> Result<Hmac<Sha256>, Box<dyn Error>> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).map_err(|_| "Invalid signing key")?;
+    mac.update(context.as_bytes());
+    mac.update(&[0]);
+    mac.update(data);
+    Ok(mac)
+}
+
+pub fn sign_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    base_path: P,
+    path: Q,
+    context: &str,
+) -> Result<(), Box<dyn Error>> {
+    let key = load_key(&base_path, SIGNING_KEY, true)?;
+    let tag = mac(&key, &read(&path)?, context)?.finalize().into_bytes();
+    write_atomic(signature_path(&path), &tag)?;
+    Ok(())
+}
+
+// Verifies a file against its signature. Files without a signature are only accepted
+// when a signature isn't required.
+pub fn verify_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    base_path: P,
+    path: Q,
+    context: &str,
+    required: bool,
+) -> Result<(), Box<dyn Error>> {
+    verify_content(&base_path, &path, &read(&path)?, context, required)
+}
+
+// Verifies content read from a file against the file's signature. Callers check the bytes
+// they go on to use, so the file can't be swapped between checking and reading it.
+pub fn verify_content<P: AsRef<Path>, Q: AsRef<Path>>(
+    base_path: P,
+    path: Q,
+    content: &[u8],
+    context: &str,
+    required: bool,
+) -> Result<(), Box<dyn Error>> {
+    let signature_path = signature_path(&path);
+    if !required && !signature_path.exists() {
+        return Ok(());
+    }
+    let tag = read(&signature_path)
+        .map_err(|_| format!("Missing signature for {}", path.as_ref().display()))?;
+    let key = load_key(&base_path, SIGNING_KEY, false)?;
+    mac(&key, content, context)?
+        .verify(&tag)
+        .map_err(|_| format!("Invalid signature for {}", path.as_ref().display()))?;
+    Ok(())
+}
+
+// Signs every model file, and the config file when asked to
+pub fn sign_files<P: AsRef<Path>>(base_path: P, config: bool) -> Result<usize, Box<dyn Error>> {
+    let mut count = 0;
+    for entry in std::fs::read_dir(base_path.as_ref().join("models"))? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |x| x != "dat") {
+            continue;
+        }
+        let _lock = lock_file(&path)?;
+        let user = path.file_stem().unwrap().to_string_lossy().to_string();
+        sign_file(&base_path, &path, &user)?;
+        count += 1;
+    }
+    if config {
+        sign_file(
+            &base_path,
+            base_path.as_ref().join("config.toml"),
+            CONFIG_CONTEXT,
+        )?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::test_base;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn encrypts_and_decrypts_models() {
+        let key = [7u8; KEY_LEN];
+        let data = encrypt(&key, b"models", "alice").unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(decrypt(&key, &data, "alice").unwrap(), b"models");
+        // The nonce is random, the same data never encrypts the same
+        assert_ne!(encrypt(&key, b"models", "alice").unwrap(), data);
+    }
+
+    #[test]
+    fn refuses_wrong_keys_and_contexts() {
+        let key = [7u8; KEY_LEN];
+        let data = encrypt(&key, b"models", "alice").unwrap();
+        assert!(decrypt(&[8u8; KEY_LEN], &data, "alice").is_err());
+        // A file copied to another user can't be read
+        assert!(decrypt(&key, &data, "bob").is_err());
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&key, &corrupted, "alice").is_err());
+        assert!(decrypt(&key, b"models", "alice").is_err());
+    }
+
+    #[test]
+    fn loads_generated_keys() {
+        let base_path = test_base("crypto-key");
+        let missing = load_key(&base_path, MODEL_KEY, false);
+        let created = load_key(&base_path, MODEL_KEY, true).unwrap();
+        let loaded = load_key(&base_path, MODEL_KEY, false).unwrap();
+        let mode = symlink_metadata(key_path(&base_path, MODEL_KEY))
+            .unwrap()
+            .mode();
+        write(key_path(&base_path, MODEL_KEY), b"short").unwrap();
+        let invalid = load_key(&base_path, MODEL_KEY, false);
+        remove_dir_all(&base_path).ok();
+        assert!(missing.is_err());
+        assert_eq!(created.len(), KEY_LEN);
+        assert_eq!(created, loaded);
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn rejects_unsigned_and_tampered_files() {
+        let base_path = test_base("crypto-sign");
+        create_dir_all(base_path.join("models")).unwrap();
+        let path = base_path.join("models").join("alice.dat");
+        write(&path, b"models").unwrap();
+
+        // Unsigned files pass until a signature is required
+        assert!(!signing_enabled(&base_path));
+        assert!(verify_file(&base_path, &path, "alice", false).is_ok());
+        assert!(verify_file(&base_path, &path, "alice", true).is_err());
+
+        sign_file(&base_path, &path, "alice").unwrap();
+        assert!(signing_enabled(&base_path));
+        assert!(verify_file(&base_path, &path, "alice", true).is_ok());
+        // Signatures are bound to the user and the content that was read
+        assert!(verify_file(&base_path, &path, "bob", true).is_err());
+        assert!(verify_content(&base_path, &path, b"planted", "alice", true).is_err());
+
+        write(&path, b"planted").unwrap();
+        let tampered = verify_file(&base_path, &path, "alice", false);
+        write(signature_path(&path), [0u8; 32]).unwrap();
+        let forged = verify_file(&base_path, &path, "alice", false);
+        remove_file(signature_path(&path)).unwrap();
+        let removed = verify_file(&base_path, &path, "alice", true);
+        remove_dir_all(&base_path).ok();
+        assert!(tampered.is_err());
+        assert!(forged.is_err());
+        assert!(removed.is_err());
+    }
+
+    #[test]
+    fn ignores_credentials_outside_holad() {
+        let base_path = test_base("crypto-credentials");
+        create_dir_all(base_path.join("credentials")).unwrap();
+        write(
+            base_path.join("credentials").join(SIGNING_KEY),
+            [1u8; KEY_LEN],
+        )
+        .unwrap();
+        std::env::set_var("CREDENTIALS_DIRECTORY", base_path.join("credentials"));
+        let path = key_path(&base_path, SIGNING_KEY);
+        // Only systemd's credentials directories are trusted
+        let used = use_credentials();
+        std::env::remove_var("CREDENTIALS_DIRECTORY");
+        remove_dir_all(&base_path).ok();
+        assert_eq!(path, base_path.join("keys").join(SIGNING_KEY));
+        assert!(used.is_err());
+    }
+}
@@ -0,0 +1,552 @@
+use crate::app::{App, ScanResult};
+use crate::audit;
+use crate::helper::user_name;
+use crate::store::{Model, Store};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::{remove_file, set_permissions, DirBuilder, Permissions},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    mem,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Socket holad listens on. Anyone can connect, requests are authorized by the peer's uid.
+pub const SOCKET_PATH: &str = "/run/hola/holad.sock";
+
+// How long a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long an authentication may take beyond the scan timeout, e.g. to load the models,
+// before the PAM module stops waiting for holad
+const RESPONSE_MARGIN: Duration = Duration::from_secs(20);
+
+// How long an enrollment waits for a face before giving up
+const ENROLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long writing an event to a subscriber may block before it's dropped
+const EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Requests are sent as a single JSON line. Only root can authenticate and subscribe to
+// events, other users can only manage their own models.
+#[derive(Deserialize, Serialize, Debug)]
+pub enum Request {
+    Authenticate { user: String },
+    Enroll { user: String, label: String },
+    Verify { user: String },
+    List { user: String },
+    Remove { user: String, model: String },
+    Subscribe,
+}
+
+impl Request {
+    fn user(&self) -> &str {
+        match self {
+            Request::Authenticate { user }
+            | Request::Enroll { user, .. }
+            | Request::Verify { user }
+            | Request::List { user }
+            | Request::Remove { user, .. } => user,
+            Request::Subscribe => "",
+        }
+    }
+}
+
+// Authentication events sent to subscribers as JSON lines
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Event {
+    Started { user: String },
+    Finished { user: String, result: ScanResult },
+}
+
+// Face model details, without the encoding
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ModelInfo {
+    pub id: usize,
+    pub label: String,
+    pub time: i64,
+    pub auto: bool,
+}
+
+impl From<&Model> for ModelInfo {
+    fn from(model: &Model) -> Self {
+        Self {
+            id: model.id,
+            label: model.label.clone(),
+            time: model.time,
+            auto: model.auto,
+        }
+    }
+}
+
+// Responses are sent as JSON lines, any number of progress events followed by a single
+// final response once the request is done
+#[derive(Deserialize, Serialize, Debug)]
+pub enum Response {
+    Progress(String),
+    Scan(ScanResult),
+    Enrolled(usize),
+    Removed(Vec<usize>),
+    Models {
+        models: Vec<ModelInfo>,
+        compatible: bool,
+    },
+    Event(Event),
+    Error(String),
+}
+
+fn send<T: Serialize>(mut stream: &UnixStream, value: &T) -> Result<(), Box<dyn Error>> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    Ok(())
+}
+
+pub fn connect() -> Result<UnixStream, Box<dyn Error>> {
+    Ok(UnixStream::connect(SOCKET_PATH)
+        .map_err(|e| format!("Failed to connect to holad, is it running? {}", e))?)
+}
+
+// Sends a request to holad, reporting progress events until the final response arrives
+pub fn request(
+    request: &Request,
+    progress: &mut dyn FnMut(&str),
+) -> Result<Response, Box<dyn Error>> {
+    send_request(&connect()?, request, progress)
+}
+
+// Like request, over an existing connection. Shutting the connection down from another
+// thread aborts the request.
+pub fn send_request(
+    stream: &UnixStream,
+    request: &Request,
+    progress: &mut dyn FnMut(&str),
+) -> Result<Response, Box<dyn Error>> {
+    send(stream, request)?;
+    for line in BufReader::new(stream).lines() {
+        match serde_json::from_str(&line?)? {
+            Response::Progress(message) => progress(&message),
+            response => return Ok(response),
+        }
+    }
+    Err("holad closed the connection".into())
+}
+
+// Like request, checking stop while waiting and skipping progress events. Returns None
+// once stop returns true, the connection is closed then, which aborts the request.
+pub fn request_until(
+    request: &Request,
+    stop: &dyn Fn() -> bool,
+) -> Result<Option<Response>, Box<dyn Error>> {
+    let mut stream = connect()?;
+    send(&stream, request)?;
+    stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        while let Some(end) = buf.iter().position(|&x| x == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            match serde_json::from_slice(&line)? {
+                Response::Progress(_) => {}
+                response => return Ok(Some(response)),
+            }
+        }
+        if stop() {
+            return Ok(None);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err("holad closed the connection".into()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Receives authentication events from holad until the connection is lost
+pub fn subscribe(on_event: &mut dyn FnMut(Event)) -> Result<(), Box<dyn Error>> {
+    let stream = connect()?;
+    send(&stream, &Request::Subscribe)?;
+    for line in BufReader::new(&stream).lines() {
+        match serde_json::from_str(&line?)? {
+            Response::Event(event) => on_event(event),
+            Response::Error(err) => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Err("holad closed the connection".into())
+}
+
+// Scans for the user's face through holad. Returns None when the daemon isn't running
+// or the socket isn't served by root, so the caller can fall back to scanning in-process.
+// Closing the connection when stop returns true makes the daemon abort the scan. The
+// scan fails when holad doesn't answer within the scan timeout and a margin.
+pub fn authenticate(
+    user: &str,
+    timeout: Duration,
+    stop: &mut dyn FnMut() -> bool,
+) -> Option<ScanResult> {
+    let mut stream = UnixStream::connect(SOCKET_PATH).ok()?;
+    match peer_uid(&stream) {
+        Ok(0) => {}
+        Ok(uid) => {
+            audit::log(&format!("ignoring {} served by uid {}", SOCKET_PATH, uid));
+            return None;
+        }
+        Err(_) => return None,
+    }
+    let request = Request::Authenticate {
+        user: user.to_string(),
+    };
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT)).ok()?;
+    send(&stream, &request).ok()?;
+    stream
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .ok()?;
+
+    let deadline = Instant::now() + timeout + RESPONSE_MARGIN;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if stop() {
+            return Some(ScanResult {
+                reason: "aborted".to_string(),
+                ..Default::default()
+            });
+        }
+        if Instant::now() > deadline {
+            audit::log(&format!(
+                "holad didn't answer in time to authenticate {}",
+                user
+            ));
+            return Some(ScanResult {
+                reason: "daemon_timeout".to_string(),
+                ..Default::default()
+            });
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                if buf.ends_with(b"\n") {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+    }
+    let reason = match serde_json::from_slice(&buf) {
+        Ok(Response::Scan(result)) => return Some(result),
+        Ok(Response::Error(err)) => err,
+        Ok(_) => "unexpected response from holad".to_string(),
+        Err(err) => format!("invalid response from holad: {}", err),
+    };
+    audit::log(&format!(
+        "holad failed to authenticate {}: {}",
+        user, reason
+    ));
+    Some(ScanResult {
+        reason: "init_failed".to_string(),
+        ..Default::default()
+    })
+}
+
+// Whether the client has hung up, which aborts the scan
+fn hung_up(stream: &UnixStream) -> bool {
+    let mut buf = [0u8; 1];
+    stream.set_nonblocking(true).ok();
+    let closed = match (&*stream).read(&mut buf) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).ok();
+    closed
+}
+
+// Uid of the connected process, as recorded by the kernel when it connected
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+// Root may do anything, other users may only manage their own models
+fn authorize(uid: u32, request: &Request) -> Result<(), String> {
+    if uid == 0 {
+        return Ok(());
+    }
+    match request {
+        Request::Authenticate { .. } => return Err("Only root can authenticate users".to_string()),
+        Request::Subscribe => return Err("Only root can subscribe to events".to_string()),
+        // Anyone at the user's unlocked session could add their own face
+        Request::Enroll { .. } => return Err("Only root can enroll models".to_string()),
+        _ => {}
+    }
+    match user_name(uid) {
+        Some(name) if name == request.user() => Ok(()),
+        _ => Err(format!(
+            "Not allowed to manage the models of user {}",
+            request.user()
+        )),
+    }
+}
+
+// Loads the user's models, keeping the dlib models loaded from previous requests
+fn load<'a, P: AsRef<Path>>(
+    base_path: P,
+    app: &'a mut Option<App>,
+    user: &str,
+) -> Result<&'a mut App, Box<dyn Error>> {
+    match app {
+        Some(a) => a.switch_user(user)?,
+        None => *app = Some(App::new(base_path, user)?),
+    }
+    Ok(app.as_mut().unwrap())
+}
+
+fn remove(store: &mut Store, model: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    store.lock()?;
+    let ids = store.find(model);
+    if ids.is_empty() {
+        return Err("Invalid ID or label".into());
+    }
+    for id in ids.iter() {
+        store.remove(*id);
+    }
+    store.save()?;
+    Ok(ids)
+}
+
+// Requests waiting for the models and the camera. Root's requests come from PAM and go
+// first, interrupting scans of other users.
+#[derive(Default)]
+struct Queue {
+    root: VecDeque<(UnixStream, Request)>,
+    users: VecDeque<(UnixStream, u32, Request)>,
+    // Users with a connection open, each may only have one
+    busy: Vec<u32>,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    subscribers: Mutex<Vec<UnixStream>>,
+}
+
+impl Shared {
+    fn root_waiting(&self) -> bool {
+        !self.queue.lock().unwrap().root.is_empty()
+    }
+
+    fn release(&self, uid: u32) {
+        self.queue.lock().unwrap().busy.retain(|&x| x != uid);
+    }
+
+    // Sends an event to all subscribers, dropping the ones that went away
+    fn publish(&self, event: Event) {
+        let response = Response::Event(event);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| send(s, &response).is_ok());
+    }
+}
+
+// Runs a request that needs the user's models. Scans of users other than root are aborted
+// when an authentication is waiting.
+fn handle<P: AsRef<Path>>(
+    base_path: P,
+    app: &mut Option<App>,
+    shared: &Shared,
+    client: &UnixStream,
+    uid: u32,
+    request: Request,
+) -> Response {
+    let a = match load(base_path, app, request.user()) {
+        Ok(a) => a,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    let interrupted = || uid != 0 && shared.root_waiting();
+    let response = match request {
+        Request::Authenticate { user } => {
+            shared.publish(Event::Started { user: user.clone() });
+            let result = a.scan(&mut || hung_up(client));
+            shared.publish(Event::Finished {
+                user,
+                result: result.clone(),
+            });
+            Response::Scan(result)
+        }
+        Request::Enroll { label, .. } => {
+            let start_time = Instant::now();
+            let mut stop =
+                || hung_up(client) || interrupted() || start_time.elapsed() > ENROLL_TIMEOUT;
+            let mut progress = |message: &str| {
+                send(client, &Response::Progress(message.to_string())).ok();
+            };
+            match a.enroll(&label, &mut stop, &mut progress) {
+                Ok(id) => Response::Enrolled(id),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
+        Request::Verify { .. } => {
+            let message = "Detecting face, please make sure you are in a well lit room";
+            send(client, &Response::Progress(message.to_string())).ok();
+            Response::Scan(a.verify(&mut || hung_up(client) || interrupted()))
+        }
+        Request::List { .. } => Response::Models {
+            models: a.store().models().iter().map(ModelInfo::from).collect(),
+            compatible: a.compatible().unwrap_or(true),
+        },
+        Request::Remove { model, .. } => match remove(a.store(), &model) {
+            Ok(ids) => Response::Removed(ids),
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Request::Subscribe => Response::Error("Unexpected request".to_string()),
+    };
+    a.store().unlock();
+    response
+}
+
+// Runs queued requests one at a time, keeping the models loaded between them
+fn work(base_path: &Path, shared: &Shared) {
+    let mut app: Option<App> = None;
+    loop {
+        let (client, uid, request) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some((client, request)) = queue.root.pop_front() {
+                    break (client, 0, request);
+                }
+                if let Some(item) = queue.users.pop_front() {
+                    break item;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+        let response = handle(base_path, &mut app, shared, &client, uid, request);
+        send(&client, &response).ok();
+        if uid != 0 {
+            shared.release(uid);
+        }
+    }
+}
+
+// Reads and authorizes a client's request on its own thread, so a slow client can't hold
+// up others, then queues it. Users other than root get one connection at a time.
+fn accept(shared: &Shared, client: UnixStream) {
+    let uid = match peer_uid(&client) {
+        Ok(uid) => uid,
+        Err(err) => {
+            let message = format!("Failed to identify client: {}", err);
+            send(&client, &Response::Error(message)).ok();
+            return;
+        }
+    };
+    if uid != 0 {
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.busy.contains(&uid) {
+            drop(queue);
+            let message = "Another request of this user is still running".to_string();
+            send(&client, &Response::Error(message)).ok();
+            return;
+        }
+        queue.busy.push(uid);
+    }
+    client.set_read_timeout(Some(REQUEST_TIMEOUT)).ok();
+    client.set_write_timeout(Some(EVENT_TIMEOUT)).ok();
+    let mut line = String::new();
+    let request = match BufReader::new(&client).read_line(&mut line) {
+        Ok(_) => serde_json::from_str::<Request>(&line)
+            .map_err(|e| format!("Invalid request: {}", e))
+            .and_then(|request| authorize(uid, &request).map(|_| request)),
+        Err(err) => Err(format!("Failed to read request: {}", err)),
+    };
+    client.set_read_timeout(None).ok();
+    match request {
+        // Subscribers are kept connected and only receive events
+        Ok(Request::Subscribe) => shared.subscribers.lock().unwrap().push(client),
+        Ok(request) => {
+            let mut queue = shared.queue.lock().unwrap();
+            match uid {
+                0 => queue.root.push_back((client, request)),
+                _ => queue.users.push_back((client, uid, request)),
+            }
+            shared.ready.notify_one();
+            return;
+        }
+        Err(err) => {
+            send(&client, &Response::Error(err)).ok();
+        }
+    }
+    if uid != 0 {
+        shared.release(uid);
+    }
+}
+
+// Serves requests, reading them concurrently and running them one at a time
+pub fn serve<P: AsRef<Path>>(base_path: P) -> Result<(), Box<dyn Error>> {
+    let socket_path = Path::new(SOCKET_PATH);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(socket_path.parent().unwrap())?;
+    if socket_path.exists() {
+        remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    set_permissions(socket_path, Permissions::from_mode(0o666))?;
+
+    let shared = Arc::new(Shared::default());
+    {
+        let shared = shared.clone();
+        let base_path = base_path.as_ref().to_path_buf();
+        thread::spawn(move || work(&base_path, &shared));
+    }
+    for stream in listener.incoming().flatten() {
+        let shared = shared.clone();
+        thread::spawn(move || accept(&shared, stream));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_root_enrolls() {
+        let request = Request::Enroll {
+            user: "alice".to_string(),
+            label: "face".to_string(),
+        };
+        assert!(authorize(0, &request).is_ok());
+        assert!(authorize(1000, &request).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::backend::{Detector, Encoder, LandmarkModel, Landmarks, Rect};
+use crate::helper::hash_file;
+use crate::recognizer::Recognizer;
+use dlib_face_recognition::*;
+use image::RgbImage;
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+// Dlib model files, as published at https://github.com/davisking/dlib-models
+const CNN_DETECTOR_MODEL: &str = "mmod_human_face_detector.dat";
+const LANDMARKS_MODEL: &str = "shape_predictor_5_face_landmarks.dat";
+const ENCODER_MODEL: &str = "dlib_face_recognition_resnet_model_v1.dat";
+
+// Dimension of the face encodings produced by the ResNet encoder
+pub const ENCODING_DIMENSION: usize = 128;
+
+// Path of a dlib model file, failing with instructions on how to get it when it's missing
+fn model_path<P: AsRef<Path>>(base_path: P, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = base_path.as_ref().join("dlib_models").join(name);
+    if !path.exists() {
+        return Err(format!(
+            "Missing dlib model file {}, build with the embed feature or download \
+             https://github.com/davisking/dlib-models/raw/master/{}.bz2 and extract it there",
+            path.display(),
+            name
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+// SHA-256 of the encoder network, identifying which network produced stored encodings
+pub fn encoder_hash<P: AsRef<Path>>(base_path: P) -> Result<String, Box<dyn Error>> {
+    Ok(hash_file(model_path(base_path, ENCODER_MODEL)?)?)
+}
+
+fn to_rect(r: &Rectangle) -> Rect {
+    (r.left, r.top, r.right, r.bottom)
+}
+
+fn to_rectangle(r: &Rect) -> Rectangle {
+    Rectangle {
+        left: r.0,
+        top: r.1,
+        right: r.2,
+        bottom: r.3,
+    }
+}
+
+// HOG detector
+impl Detector for FaceDetector {
+    fn detect(&self, image: &RgbImage) -> Vec<Rect> {
+        let matrix = ImageMatrix::from_image(image);
+        self.face_locations(&matrix).iter().map(to_rect).collect()
+    }
+}
+
+// MMOD CNN detector
+impl Detector for FaceDetectorCnn {
+    fn detect(&self, image: &RgbImage) -> Vec<Rect> {
+        let matrix = ImageMatrix::from_image(image);
+        self.face_locations(&matrix).iter().map(to_rect).collect()
+    }
+}
+
+// Loads the HOG detector, shape predictor and encoder, and the CNN detector when use_cnn is set
+pub fn load<P: AsRef<Path>>(base_path: P, use_cnn: bool) -> Result<Recognizer, Box<dyn Error>> {
+    let mut recognizer = Recognizer::new(
+        Box::new(FaceDetector::new()),
+        Box::new(landmark_predictor(&base_path)?),
+        Box::new(DlibEncoder::load(&base_path)?),
+    );
+    if use_cnn {
+        recognizer.load_cnn(&base_path)?;
+    }
+    Ok(recognizer)
+}
+
+pub fn cnn_detector<P: AsRef<Path>>(base_path: P) -> Result<FaceDetectorCnn, Box<dyn Error>> {
+    Ok(FaceDetectorCnn::new(model_path(
+        base_path,
+        CNN_DETECTOR_MODEL,
+    )?)?)
+}
+
+// 5 point shape predictor, keeping dlib's landmarks for the encoder
+impl LandmarkModel for LandmarkPredictor {
+    fn landmarks(&self, image: &RgbImage, faces: &[Rect]) -> Vec<Landmarks> {
+        let matrix = ImageMatrix::from_image(image);
+        faces
+            .iter()
+            .map(|face| {
+                let landmarks = self.face_landmarks(&matrix, &to_rectangle(face));
+                Landmarks {
+                    face: *face,
+                    points: landmarks.iter().map(|p| (p.x(), p.y())).collect(),
+                    native: Some(Box::new(landmarks)),
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn landmark_predictor<P: AsRef<Path>>(
+    base_path: P,
+) -> Result<LandmarkPredictor, Box<dyn Error>> {
+    Ok(LandmarkPredictor::new(model_path(
+        base_path,
+        LANDMARKS_MODEL,
+    )?)?)
+}
+
+// ResNet encoder, only works with landmarks of the dlib shape predictor
+pub struct DlibEncoder {
+    network: FaceEncoderNetwork,
+    hash: String,
+}
+
+impl DlibEncoder {
+    pub fn load<P: AsRef<Path>>(base_path: P) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            network: FaceEncoderNetwork::new(model_path(&base_path, ENCODER_MODEL)?)?,
+            hash: encoder_hash(&base_path)?,
+        })
+    }
+}
+
+impl Encoder for DlibEncoder {
+    fn name(&self) -> &str {
+        &self.hash
+    }
+
+    fn dimension(&self) -> usize {
+        ENCODING_DIMENSION
+    }
+
+    fn encode(&self, image: &RgbImage, landmarks: &[Landmarks]) -> Vec<Vec<f64>> {
+        let matrix = ImageMatrix::from_image(image);
+        landmarks
+            .iter()
+            .filter_map(|l| l.native.as_ref()?.downcast_ref::<FaceLandmarks>())
+            .filter_map(|l| {
+                let encodings =
+                    self.network
+                        .get_face_encodings(&matrix, std::slice::from_ref(l), 0);
+                encodings.first().map(|e| e.as_ref().to_vec())
+            })
+            .collect()
+    }
+}
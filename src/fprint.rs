@@ -0,0 +1,656 @@
+use crate::app::ScanResult;
+use crate::audit::{self, Attempt};
+use crate::auth::{self, Outcome, Silent};
+use crate::bus::{self, AuthError, Backend};
+use crate::config::{self, Config};
+use crate::daemon::{ModelInfo, Request, Response};
+use crate::security;
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+use zbus::{dbus_interface, fdo, Connection, DBusError, Message, MessageHeader};
+use zvariant::OwnedObjectPath;
+
+pub const SERVICE_NAME: &str = "net.reactivated.Fprint";
+pub const MANAGER_PATH: &str = "/net/reactivated/Fprint/Manager";
+pub const DEVICE_PATH: &str = "/net/reactivated/Fprint/Device/0";
+const DEVICE_INTERFACE: &str = "net.reactivated.Fprint.Device";
+
+// Finger names understood by fprintd clients
+const FINGERS: [&str; 10] = [
+    "left-thumb",
+    "left-index-finger",
+    "left-middle-finger",
+    "left-ring-finger",
+    "left-little-finger",
+    "right-thumb",
+    "right-index-finger",
+    "right-middle-finger",
+    "right-ring-finger",
+    "right-little-finger",
+];
+
+// Models enrolled through D-Bus are labelled with the finger name, other models show up
+// as the right index finger
+fn finger_of(label: &str) -> &str {
+    match FINGERS.contains(&label) {
+        true => label,
+        false => "right-index-finger",
+    }
+}
+
+#[derive(DBusError, Debug)]
+#[dbus_error(prefix = "net.reactivated.Fprint.Error")]
+pub enum Error {
+    ZBus(zbus::Error),
+    PermissionDenied(String),
+    AlreadyInUse(String),
+    Internal(String),
+    ClaimDevice(String),
+    NoEnrolledPrints(String),
+    NoActionInProgress(String),
+    InvalidFingername(String),
+}
+
+impl From<AuthError> for Error {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Internal(err) => Error::Internal(err),
+            AuthError::PermissionDenied(err) => Error::PermissionDenied(err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn list_models(backend: &dyn Backend, user: &str) -> Result<Vec<ModelInfo>> {
+    let request = Request::List {
+        user: user.to_string(),
+    };
+    match bus::request(backend, request, Error::Internal)? {
+        Response::Models { models, .. } => Ok(models.into_iter().filter(|m| !m.auto).collect()),
+        _ => Err(Error::Internal(
+            "Unexpected response from holad".to_string(),
+        )),
+    }
+}
+
+fn remove_models(backend: &dyn Backend, user: &str, models: &[ModelInfo]) -> Result<()> {
+    for m in models.iter() {
+        let request = Request::Remove {
+            user: user.to_string(),
+            model: m.id.to_string(),
+        };
+        bus::request(backend, request, Error::Internal)?;
+    }
+    Ok(())
+}
+
+// Checks the face under the same policy as the PAM module, the lockout, required passwords
+// and the other core settings, and logs the attempt like a login
+fn verify(
+    base_path: &Path,
+    backend: &dyn Backend,
+    user: &str,
+    stop: &dyn Fn() -> bool,
+) -> &'static str {
+    let mut attempt = Attempt::new(user, "fprintd".to_string(), String::new(), String::new());
+    let start_time = Instant::now();
+    let mut json = false;
+    let outcome = match policy(base_path, user) {
+        Ok(config) => {
+            json = config.log.json;
+            let request = Request::Authenticate {
+                user: user.to_string(),
+            };
+            let mut scan = || match backend.run(&request, stop)? {
+                Some(Response::Scan(result)) => Ok(result),
+                Some(_) => Err("Unexpected response from holad".to_string()),
+                None => Ok(ScanResult {
+                    reason: "aborted".to_string(),
+                    ..Default::default()
+                }),
+            };
+            auth::authenticate(
+                base_path,
+                user,
+                &config,
+                &mut attempt,
+                &mut Silent,
+                &mut scan,
+            )
+        }
+        Err(reason) => {
+            attempt.reason = reason.to_string();
+            Outcome::Unavailable
+        }
+    };
+    attempt.finish(outcome == Outcome::Identified, start_time.elapsed());
+    audit::record(base_path, &attempt, json);
+    match outcome {
+        Outcome::Identified => "verify-match",
+        Outcome::Failed => "verify-no-match",
+        _ => "verify-unknown-error",
+    }
+}
+
+// Loads the config the policy comes from, refusing files someone other than root could
+// have modified
+fn policy(base_path: &Path, user: &str) -> std::result::Result<Config, &'static str> {
+    let issues = security::check_permissions(base_path);
+    if !issues.is_empty() {
+        for issue in issues.iter() {
+            audit::log(&format!(
+                "refusing to verify {}: {} is {}",
+                user,
+                issue.path.display(),
+                issue.problem()
+            ));
+        }
+        return Err("insecure_permissions");
+    }
+    config::load(base_path).map_err(|err| {
+        audit::log(&format!("refusing to verify {}: {}", user, err));
+        "init_failed"
+    })
+}
+
+pub struct Manager;
+
+#[dbus_interface(name = "net.reactivated.Fprint.Manager")]
+impl Manager {
+    fn get_devices(&self) -> Vec<OwnedObjectPath> {
+        vec![OwnedObjectPath::try_from(DEVICE_PATH).unwrap()]
+    }
+
+    fn get_default_device(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(DEVICE_PATH).unwrap()
+    }
+}
+
+// User the device is claimed for, and the client that claimed it
+struct Claim {
+    user: String,
+    sender: String,
+}
+
+// Stop flag of the running verification or enrollment
+type Action = Arc<Mutex<Option<Arc<AtomicBool>>>>;
+
+// Stops the running action, returning whether there was one
+fn stop_action(action: &Action) -> bool {
+    match action.lock().unwrap().take() {
+        Some(stopped) => {
+            stopped.store(true, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+// Asks the bus to tell the connection when clients leave, which ClaimWatcher handles
+pub fn watch_clients(connection: &Connection) -> zbus::Result<()> {
+    fdo::DBusProxy::new(connection)?.add_match(
+        "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',\
+         member='NameOwnerChanged',arg2=''",
+    )?;
+    Ok(())
+}
+
+// Releases the device when the client that claimed it leaves the bus without releasing
+// it, so a crashed client can't keep the device claimed
+#[derive(Clone)]
+pub struct ClaimWatcher {
+    claim: Arc<Mutex<Option<Claim>>>,
+    action: Action,
+}
+
+impl ClaimWatcher {
+    // Handles a message the object server didn't handle, ignoring all but NameOwnerChanged
+    pub fn handle(&self, message: &Message) {
+        let header = match message.header() {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+        let from_bus = matches!(header.sender(), Ok(Some(s)) if s == "org.freedesktop.DBus");
+        if !from_bus || !matches!(header.member(), Ok(Some(m)) if m == "NameOwnerChanged") {
+            return;
+        }
+        let (name, _, owner) = match message.body::<(String, String, String)>() {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let mut claim = self.claim.lock().unwrap();
+        if owner.is_empty() && claim.as_ref().map_or(false, |c| c.sender == name) {
+            *claim = None;
+            stop_action(&self.action);
+        }
+    }
+}
+
+// The face camera, presented as a single press-type fingerprint reader
+pub struct Device {
+    connection: Connection,
+    base_path: PathBuf,
+    backend: Arc<dyn Backend>,
+    claim: Arc<Mutex<Option<Claim>>>,
+    action: Action,
+}
+
+impl Device {
+    pub fn new<P: AsRef<Path>>(
+        connection: Connection,
+        base_path: P,
+        backend: Arc<dyn Backend>,
+    ) -> Self {
+        Self {
+            connection,
+            base_path: base_path.as_ref().to_path_buf(),
+            backend,
+            claim: Arc::new(Mutex::new(None)),
+            action: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn claim_watcher(&self) -> ClaimWatcher {
+        ClaimWatcher {
+            claim: self.claim.clone(),
+            action: self.action.clone(),
+        }
+    }
+
+    fn authorize(&self, header: &MessageHeader, user: &str) -> Result<String> {
+        Ok(bus::authorize(&self.connection, header, user)?)
+    }
+
+    // User the device was claimed for by the caller
+    fn claimed_user(&self, header: &MessageHeader) -> Result<String> {
+        let sender = bus::sender(header).map_err(Error::Internal)?;
+        match self.claim.lock().unwrap().as_ref() {
+            Some(claim) if claim.sender == sender => Ok(claim.user.clone()),
+            _ => Err(Error::ClaimDevice("Device was not claimed".to_string())),
+        }
+    }
+
+    // Runs an action in the background, emitting the status signal with its result once
+    // it's done. The action gets a function telling whether it was stopped.
+    fn start<F>(&self, signal: &'static str, action: F) -> Result<()>
+    where
+        F: FnOnce(&dyn Fn() -> bool) -> &'static str + Send + 'static,
+    {
+        let mut running = self.action.lock().unwrap();
+        if running.is_some() {
+            return Err(Error::AlreadyInUse(
+                "An action is already in progress".to_string(),
+            ));
+        }
+        let stopped = Arc::new(AtomicBool::new(false));
+        *running = Some(stopped.clone());
+        let connection = self.connection.clone();
+        let running = self.action.clone();
+        thread::spawn(move || {
+            let result = action(&|| stopped.load(Ordering::SeqCst));
+            // Stopped actions don't report a status
+            {
+                let mut running = running.lock().unwrap();
+                match running.as_ref() {
+                    Some(x) if Arc::ptr_eq(x, &stopped) => *running = None,
+                    _ => return,
+                }
+            }
+            connection
+                .emit_signal(None, DEVICE_PATH, DEVICE_INTERFACE, signal, &(result, true))
+                .ok();
+        });
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        match stop_action(&self.action) {
+            true => Ok(()),
+            false => Err(Error::NoActionInProgress(
+                "No action in progress".to_string(),
+            )),
+        }
+    }
+}
+
+#[dbus_interface(name = "net.reactivated.Fprint.Device")]
+impl Device {
+    fn list_enrolled_fingers(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        username: &str,
+    ) -> Result<Vec<String>> {
+        let user = self.authorize(&header, username)?;
+        let mut fingers: Vec<String> = Vec::new();
+        for m in list_models(&*self.backend, &user)?.iter() {
+            let finger = finger_of(&m.label).to_string();
+            if !fingers.contains(&finger) {
+                fingers.push(finger);
+            }
+        }
+        if fingers.is_empty() {
+            return Err(Error::NoEnrolledPrints(
+                "No face models enrolled".to_string(),
+            ));
+        }
+        Ok(fingers)
+    }
+
+    fn delete_enrolled_fingers(
+        &mut self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        username: &str,
+    ) -> Result<()> {
+        let user = self.authorize(&header, username)?;
+        remove_models(&*self.backend, &user, &list_models(&*self.backend, &user)?)
+    }
+
+    fn delete_enrolled_fingers2(
+        &mut self,
+        #[zbus(header)] header: MessageHeader<'_>,
+    ) -> Result<()> {
+        let user = self.claimed_user(&header)?;
+        remove_models(&*self.backend, &user, &list_models(&*self.backend, &user)?)
+    }
+
+    fn delete_enrolled_finger(
+        &mut self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        finger_name: &str,
+    ) -> Result<()> {
+        let user = self.claimed_user(&header)?;
+        let models: Vec<ModelInfo> = list_models(&*self.backend, &user)?
+            .into_iter()
+            .filter(|m| finger_of(&m.label) == finger_name)
+            .collect();
+        if models.is_empty() {
+            return Err(Error::NoEnrolledPrints(format!(
+                "No {} enrolled",
+                finger_name
+            )));
+        }
+        remove_models(&*self.backend, &user, &models)
+    }
+
+    fn claim(&mut self, #[zbus(header)] header: MessageHeader<'_>, username: &str) -> Result<()> {
+        let user = self.authorize(&header, username)?;
+        let sender = bus::sender(&header).map_err(Error::Internal)?;
+        let mut claim = self.claim.lock().unwrap();
+        if claim.is_some() {
+            return Err(Error::AlreadyInUse(
+                "Device was already claimed".to_string(),
+            ));
+        }
+        *claim = Some(Claim { user, sender });
+        Ok(())
+    }
+
+    fn release(&mut self, #[zbus(header)] header: MessageHeader<'_>) -> Result<()> {
+        self.claimed_user(&header)?;
+        self.stop().ok();
+        *self.claim.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn verify_start(
+        &mut self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        _finger_name: &str,
+    ) -> Result<()> {
+        let user = self.claimed_user(&header)?;
+        if list_models(&*self.backend, &user)?.is_empty() {
+            return Err(Error::NoEnrolledPrints(
+                "No face models enrolled".to_string(),
+            ));
+        }
+        let base_path = self.base_path.clone();
+        let backend = self.backend.clone();
+        self.start("VerifyStatus", move |stop| {
+            verify(&base_path, &*backend, &user, stop)
+        })?;
+        self.verify_finger_selected("any").map_err(Error::ZBus)?;
+        Ok(())
+    }
+
+    fn verify_stop(&mut self, #[zbus(header)] header: MessageHeader<'_>) -> Result<()> {
+        self.claimed_user(&header)?;
+        self.stop()
+    }
+
+    fn enroll_start(
+        &mut self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        finger_name: &str,
+    ) -> Result<()> {
+        let user = self.claimed_user(&header)?;
+        // Anyone at the user's unlocked session could add their own face
+        bus::authorize_root(&self.connection, &header)?;
+        if !FINGERS.contains(&finger_name) {
+            return Err(Error::InvalidFingername(format!(
+                "Invalid finger name {}",
+                finger_name
+            )));
+        }
+        let request = Request::Enroll {
+            user,
+            label: finger_name.to_string(),
+        };
+        let backend = self.backend.clone();
+        self.start("EnrollStatus", move |stop| {
+            match backend.run(&request, stop) {
+                Ok(Some(Response::Enrolled(_))) => "enroll-completed",
+                _ => "enroll-failed",
+            }
+        })
+    }
+
+    fn enroll_stop(&mut self, #[zbus(header)] header: MessageHeader<'_>) -> Result<()> {
+        self.claimed_user(&header)?;
+        self.stop()
+    }
+
+    #[dbus_interface(signal)]
+    fn verify_finger_selected(&self, finger_name: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn verify_status(&self, result: &str, done: bool) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn enroll_status(&self, result: &str, done: bool) -> zbus::Result<()>;
+
+    #[dbus_interface(property, name = "name")]
+    fn name(&self) -> &str {
+        "Hola face authentication"
+    }
+
+    #[dbus_interface(property, name = "num-enroll-stages")]
+    fn num_enroll_stages(&self) -> i32 {
+        1
+    }
+
+    #[dbus_interface(property, name = "scan-type")]
+    fn scan_type(&self) -> &str {
+        "press"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use crate::helper::{test_base, user_name};
+    use crate::mock::face_frame;
+    use crate::recognizer::Recognizer;
+    use image::RgbImage;
+    use std::{
+        fs::{read_to_string, remove_dir_all},
+        io::{BufRead, BufReader},
+        process::{Command, Stdio},
+        sync::mpsc,
+        time::Duration,
+    };
+    use zbus::ObjectServer;
+
+    // Answers requests with the mock recognizer, playing back frames instead of the camera
+    struct MockBackend {
+        base_path: PathBuf,
+        frames: Vec<RgbImage>,
+    }
+
+    impl MockBackend {
+        fn open(&self, user: &str) -> std::result::Result<App, String> {
+            App::with_backend(&self.base_path, user, Recognizer::mock, self.frames.clone())
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    impl Backend for MockBackend {
+        fn run(
+            &self,
+            request: &Request,
+            stop: &dyn Fn() -> bool,
+        ) -> std::result::Result<Option<Response>, String> {
+            match request {
+                Request::List { user } => {
+                    let mut app = self.open(user)?;
+                    Ok(Some(Response::Models {
+                        models: app.store().models().iter().map(ModelInfo::from).collect(),
+                        compatible: app.compatible().map_err(|e| e.to_string())?,
+                    }))
+                }
+                Request::Authenticate { user } => {
+                    Ok(Some(Response::Scan(self.open(user)?.scan(&mut || stop()))))
+                }
+                _ => Err("Unsupported request".to_string()),
+            }
+        }
+    }
+
+    fn call<B: serde::Serialize + zvariant::Type>(
+        connection: &Connection,
+        method: &str,
+        body: &B,
+    ) -> zbus::Result<Message> {
+        connection.call_method(
+            Some(SERVICE_NAME),
+            DEVICE_PATH,
+            Some(DEVICE_INTERFACE),
+            method,
+            body,
+        )
+    }
+
+    #[test]
+    fn verify_on_private_bus() {
+        // Needs root to pass the permission checks and dbus-daemon for a private bus
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let mut bus = match Command::new("dbus-daemon")
+            .args(&["--session", "--print-address", "--nofork"])
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(bus) => bus,
+            Err(_) => return,
+        };
+        let mut address = String::new();
+        BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let base_path = test_base("fprint");
+        let user = user_name(0).unwrap();
+        let frames = vec![face_frame([200, 150, 100])];
+        App::with_backend(&base_path, &user, Recognizer::mock, frames.clone())
+            .unwrap()
+            .enroll("right-index-finger", &mut || false, &mut |_| {})
+            .unwrap();
+
+        let (ready, started) = mpsc::channel();
+        let (server_address, server_path) = (address.clone(), base_path.clone());
+        thread::spawn(move || {
+            let connection = Connection::new_for_address(&server_address, true).unwrap();
+            fdo::DBusProxy::new(&connection)
+                .unwrap()
+                .request_name(SERVICE_NAME, fdo::RequestNameFlags::ReplaceExisting.into())
+                .unwrap();
+            watch_clients(&connection).unwrap();
+            let backend = Arc::new(MockBackend {
+                base_path: server_path.clone(),
+                frames,
+            });
+            let device = Device::new(connection.clone(), &server_path, backend);
+            let claims = device.claim_watcher();
+            let mut object_server = ObjectServer::new(&connection);
+            object_server.at(DEVICE_PATH, device).unwrap();
+            ready.send(()).unwrap();
+            loop {
+                match object_server.try_handle_next() {
+                    Ok(Some(message)) => claims.handle(&message),
+                    Ok(None) => {}
+                    Err(_) => return,
+                }
+            }
+        });
+        started.recv().unwrap();
+
+        // A client leaving the bus without releasing its claim frees the device
+        let first = Connection::new_for_address(&address, true).unwrap();
+        call(&first, "Claim", &"").unwrap();
+        drop(first);
+        let client = Connection::new_for_address(&address, true).unwrap();
+        let mut claimed = false;
+        for _ in 0..50 {
+            if call(&client, "Claim", &"").is_ok() {
+                claimed = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let fingers =
+            call(&client, "ListEnrolledFingers", &"").and_then(|m| Ok(m.body::<Vec<String>>()?));
+        fdo::DBusProxy::new(&client)
+            .unwrap()
+            .add_match(
+                "type='signal',interface='net.reactivated.Fprint.Device',member='VerifyStatus'",
+            )
+            .unwrap();
+        call(&client, "VerifyStart", &"any").unwrap();
+        let (status, received) = mpsc::channel();
+        thread::spawn(move || loop {
+            let message = match client.receive_message() {
+                Ok(m) => m,
+                Err(_) => return,
+            };
+            let header = match message.header() {
+                Ok(h) => h,
+                Err(_) => continue,
+            };
+            if matches!(header.member(), Ok(Some(m)) if m == "VerifyStatus") {
+                status.send(message.body::<(String, bool)>().ok()).ok();
+                return;
+            }
+        });
+        let result = received.recv_timeout(Duration::from_secs(30));
+        let attempts = read_to_string(audit::log_file_path(&base_path)).unwrap_or_default();
+        bus.kill().ok();
+        remove_dir_all(&base_path).ok();
+
+        assert!(claimed);
+        assert_eq!(fingers.unwrap(), vec!["right-index-finger".to_string()]);
+        assert_eq!(result.unwrap(), Some(("verify-match".to_string(), true)));
+        assert!(attempts.contains("\"service\":\"fprintd\""));
+    }
+}
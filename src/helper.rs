@@ -1,4 +1,15 @@
+use fs2::FileExt;
 use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::CStr,
+    fs::{rename, File, OpenOptions},
+    io::{self, Write},
+    mem::MaybeUninit,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    ptr,
+};
 
 pub fn get_pb() -> ProgressBar {
     let pb = ProgressBar::new_spinner();
@@ -18,3 +29,80 @@ pub fn get_pb() -> ProgressBar {
     );
     pb
 }
+
+// Replaces a file by writing a synced temporary file and renaming it over the original
+pub fn write_atomic<P: AsRef<Path>>(path: P, content: &[u8]) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Takes an exclusive advisory lock on a .lock file next to the given path,
+// released when the returned file is dropped
+pub fn lock_file<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(0o600)
+        .open(path.as_ref().with_extension("lock"))?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+// Looks up the login name of a user ID
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(
+            uid,
+            passwd.as_mut_ptr(),
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr((*result).pw_name) };
+    Some(name.to_string_lossy().to_string())
+}
+
+// SHA-256 of a file's content in hex
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Creates an empty base directory for a test, with the default config except that face
+// login isn't disabled by the environment, scans time out after a second and attempts are
+// logged to the JSON file
+#[cfg(test)]
+pub fn test_base(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("hola-test-{}-{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+    let config = include_str!("../pam_hola/config.toml")
+        .replace("ignore_ssh = true", "ignore_ssh = false")
+        .replace("ignore_closed_lid = true", "ignore_closed_lid = false")
+        .replace("timeout = 10", "timeout = 1")
+        .replace("json = false", "json = true");
+    std::fs::write(path.join("config.toml"), config).unwrap();
+    path
+}
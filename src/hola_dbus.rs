@@ -0,0 +1,45 @@
+use pam_hola::{bus, fprint, manager};
+use std::{env, error::Error, sync::Arc, thread};
+use zbus::{fdo, Connection, ObjectServer};
+
+// Serves the D-Bus interfaces, on the session bus with --session for testing
+fn serve(session: bool) -> Result<(), Box<dyn Error>> {
+    let base_path = "/lib/security/pam_hola";
+    let connection = match session {
+        true => Connection::new_session()?,
+        false => Connection::new_system()?,
+    };
+    let proxy = fdo::DBusProxy::new(&connection)?;
+    for name in [fprint::SERVICE_NAME, manager::SERVICE_NAME].iter() {
+        proxy.request_name(name, fdo::RequestNameFlags::ReplaceExisting.into())?;
+    }
+    fprint::watch_clients(&connection)?;
+    let mut object_server = ObjectServer::new(&connection);
+    let device = fprint::Device::new(connection.clone(), base_path, Arc::new(bus::Holad));
+    let claims = device.claim_watcher();
+    object_server.at(fprint::MANAGER_PATH, fprint::Manager)?;
+    object_server.at(fprint::DEVICE_PATH, device)?;
+    object_server.at(
+        manager::MANAGER_PATH,
+        manager::Manager::new(connection.clone(), base_path),
+    )?;
+
+    let signals = connection.clone();
+    thread::spawn(move || manager::forward_events(signals));
+    loop {
+        match object_server.try_handle_next() {
+            // Messages that aren't method calls, like clients leaving the bus
+            Ok(Some(message)) => claims.handle(&message),
+            Ok(None) => {}
+            Err(err) => eprintln!("Error handling message: {}", err),
+        }
+    }
+}
+
+fn main() {
+    let session = env::args().any(|x| x == "--session");
+    if let Err(err) = serve(session) {
+        eprintln!("Error running hola-dbus: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,13 @@
+use pam_hola::{crypto, daemon};
+use std::path::Path;
+
+fn main() {
+    let base_path = Path::new("/lib/security/pam_hola");
+    if let Err(err) = crypto::use_credentials() {
+        eprintln!("{}", err);
+    }
+    if let Err(err) = daemon::serve(base_path) {
+        eprintln!("Error running holad: {}", err);
+        std::process::exit(1);
+    }
+}
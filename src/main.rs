@@ -1,21 +1,23 @@
-mod app;
-mod helper;
-use app::*;
 use chrono::{Local, TimeZone};
-use clap::{Clap, ValueHint};
+use clap::{Clap, FromArgMatches, IntoApp, ValueHint};
 use console::style;
-use helper::get_pb;
+use indicatif::ProgressBar;
+use pam_hola::app::App;
+use pam_hola::capture::{Capture, Recording, Source};
+use pam_hola::config::{self, Config};
+use pam_hola::daemon::{self, ModelInfo, Request, Response};
+use pam_hola::helper::{get_pb, user_name};
+use pam_hola::state::StateFile;
+use pam_hola::{audit, benchmark, crypto, recognizer, security, snapshot};
 use prettytable::{cell, row, Table};
 use std::{
-    path::Path,
-    thread::sleep,
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 use subprocess::Exec;
 
 #[derive(Clap)]
 #[clap(
-    version = "1.0",
     author = "Saanu Reghunadh",
     about = "Windows Hello™ style facial authentication for Linux written in Rust"
 )]
@@ -32,6 +34,93 @@ enum SubCommand {
     Model(ModelOpts),
     #[clap(about = "Open configuration file in default text editor")]
     Config(EmptyOpts),
+    #[clap(about = "Sign face models, and optionally the config file, against tampering")]
+    Sign(SignOpts),
+    #[clap(about = "Check ownership and permissions of Hola files")]
+    Doctor(DoctorOpts),
+    #[clap(about = "Reset the failed attempt lockout of the user")]
+    ResetLockout(EmptyOpts),
+    #[clap(about = "Show recent authentication attempts")]
+    Log(LogOpts),
+    #[clap(about = "Snapshots of authentication attempts related commands")]
+    Snapshots(SnapshotsOpts),
+    #[clap(about = "Measure how fast frames are processed with each detector and height")]
+    Benchmark(BenchmarkOpts),
+}
+
+#[derive(Clap)]
+struct BenchmarkOpts {
+    #[clap(
+        short,
+        long,
+        default_value = "50",
+        about = "Number of frames to measure for each detector and height"
+    )]
+    frames: usize,
+    #[clap(
+        long,
+        value_hint = ValueHint::DirPath,
+        about = "Play back the images in this directory instead of capturing from the camera"
+    )]
+    dir: Option<PathBuf>,
+    #[clap(
+        long,
+        use_delimiter = true,
+        default_value = "240,320,480",
+        about = "Heights frames are resized to"
+    )]
+    heights: Vec<u32>,
+    #[clap(long, about = "Only measure this detector")]
+    detector: Option<String>,
+}
+
+#[derive(Clap)]
+struct SnapshotsOpts {
+    #[clap(subcommand)]
+    subcmd: SnapshotsSubCommand,
+}
+
+#[derive(Clap)]
+enum SnapshotsSubCommand {
+    #[clap(about = "List all snapshots")]
+    List(EmptyOpts),
+    #[clap(about = "Open a snapshot in the default image viewer")]
+    Show(InputSnapshotOpts),
+    #[clap(about = "Remove all snapshots")]
+    Purge(EmptyOpts),
+}
+
+#[derive(Clap)]
+struct InputSnapshotOpts {
+    #[clap(about = "Name of the snapshot")]
+    name: String,
+}
+
+#[derive(Clap)]
+struct LogOpts {
+    #[clap(
+        short,
+        long,
+        default_value = "20",
+        about = "Number of attempts to show"
+    )]
+    count: usize,
+    #[clap(long, about = "Only show failed attempts")]
+    failed: bool,
+    #[clap(long, about = "Show attempts of all users")]
+    all_users: bool,
+}
+
+#[derive(Clap)]
+struct DoctorOpts {
+    #[clap(long, about = "Fix bad ownership and permissions")]
+    fix: bool,
+}
+
+#[derive(Clap)]
+struct SignOpts {
+    #[clap(long, about = "Also sign the configuration file")]
+    config: bool,
 }
 
 #[derive(Clap)]
@@ -45,13 +134,17 @@ enum ModelSubCommand {
     #[clap(about = "Add a face model")]
     Add(InputStringOpts),
     #[clap(about = "Remove a face model")]
-    Remove(InputIntOpts),
+    Remove(InputModelOpts),
     #[clap(about = "List all face models")]
     List(EmptyOpts),
     #[clap(about = "Test against all face models")]
     Check(EmptyOpts),
     #[clap(about = "Clear all face models")]
     Clear(EmptyOpts),
+    #[clap(about = "Remove all auto-learned face models")]
+    Purge(EmptyOpts),
+    #[clap(about = "Encrypt existing plaintext face models of all users")]
+    Encrypt(EmptyOpts),
 }
 
 #[derive(Clap)]
@@ -61,16 +154,173 @@ struct InputStringOpts {
 }
 
 #[derive(Clap)]
-struct InputIntOpts {
-    #[clap(about = "ID of the face model")]
-    id: usize,
+struct InputModelOpts {
+    #[clap(about = "ID or label of the face model")]
+    model: String,
 }
 
 #[derive(Clap)]
 struct EmptyOpts {}
 
+fn error_message<E: std::fmt::Display>(message: &str, err: E) -> String {
+    style(format!("{}: {}", message, err))
+        .bold()
+        .red()
+        .to_string()
+}
+
+// Initializes the app, optionally locking the user's models for modification
+fn init_app(pb: &ProgressBar, base_path: &Path, user: &str, lock: bool) -> Option<App> {
+    let mut a = match App::new(base_path, user) {
+        Ok(a) => a,
+        Err(err) => {
+            pb.finish_with_message(&error_message("Error initializing", err));
+            return None;
+        }
+    };
+    if lock {
+        if let Err(err) = a.store().lock() {
+            pb.finish_with_message(&error_message("Error locking the models", err));
+            return None;
+        }
+    }
+    Some(a)
+}
+
+// Median, 90th and 99th percentile of latencies in milliseconds
+fn latencies(samples: &[Duration]) -> String {
+    let ms = |fraction| benchmark::percentile(samples, fraction).as_secs_f64() * 1000.0;
+    format!("{:.1} / {:.1} / {:.1}", ms(0.5), ms(0.9), ms(0.99))
+}
+
+fn incompatible_message() -> String {
+    style("Face models were created with a different encoder, run `sudo hola model clear` and re-enroll")
+        .bold()
+        .red()
+        .to_string()
+}
+
+fn print_models(user: &str, models: &[ModelInfo], compatible: bool) {
+    println!("Models for user {}", style(user).bold().blue());
+    if !compatible {
+        println!("{}", incompatible_message());
+    }
+    let mut table = Table::new();
+    table.add_row(row!["ID", "Label", "Type", "Added on"]);
+    for m in models.iter() {
+        table.add_row(row![
+            style(m.id).bold().dim().to_string(),
+            style(&m.label).bold().to_string(),
+            match m.auto {
+                true => style("auto-learned").dim().to_string(),
+                false => "enrolled".to_string(),
+            },
+            Local.timestamp(m.time, 0).to_string(),
+        ]);
+    }
+    table.printstd();
+}
+
+// Runs a model command through holad, which lets users manage their own models without
+// root. Adding a model still needs root.
+fn remote_model_command(user: &str, subcmd: ModelSubCommand) {
+    let pb = get_pb();
+    let user = user.to_string();
+    let request = match subcmd {
+        ModelSubCommand::Remove(x) => Request::Remove {
+            user: user.clone(),
+            model: x.model,
+        },
+        ModelSubCommand::List(_) => Request::List { user: user.clone() },
+        ModelSubCommand::Check(_) => Request::Verify { user: user.clone() },
+        _ => {
+            return pb.finish_with_message(
+                &style("Please run this command as root")
+                    .bold()
+                    .red()
+                    .to_string(),
+            );
+        }
+    };
+    pb.set_message("Connecting to holad");
+    let start_time = Instant::now();
+    let mut progress = |message: &str| pb.set_message(message);
+    let response = match daemon::request(&request, &mut progress) {
+        Ok(r) => r,
+        Err(err) => return pb.finish_with_message(&error_message("Error", err)),
+    };
+    match response {
+        Response::Enrolled(id) => pb.finish_with_message(&format!(
+            "Successfully added model for user {} with ID {}",
+            style(&user).bold().blue(),
+            style(id).bold().green()
+        )),
+        Response::Removed(ids) => pb.finish_with_message(&format!(
+            "Successfully removed model for user {} with ID {}",
+            style(&user).bold().blue(),
+            style(
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+            .bold()
+            .green()
+        )),
+        Response::Models { models, compatible } => {
+            if models.is_empty() {
+                return pb.finish_with_message(&format!(
+                    "No models found for user {}",
+                    style(&user).bold().blue()
+                ));
+            }
+            pb.finish_and_clear();
+            print_models(&user, &models, compatible);
+        }
+        Response::Scan(result) => match result.reason.as_str() {
+            "identified" => pb.finish_with_message(&format!(
+                "Identified face as {} in {:?}",
+                style(&user).bold().blue(),
+                start_time.elapsed()
+            )),
+            "no_models" => pb.finish_with_message(&format!(
+                "No models found for user {}",
+                style(&user).bold().blue()
+            )),
+            "incompatible_models" => pb.finish_with_message(&incompatible_message()),
+            _ => pb.finish_with_message(
+                &style(format!("Face not identified ({})", result.reason))
+                    .bold()
+                    .red()
+                    .to_string(),
+            ),
+        },
+        Response::Error(err) => pb.finish_with_message(&error_message("Error", err)),
+        Response::Progress(_) | Response::Event(_) => pb.finish_and_clear(),
+    }
+}
+
+// Version with the backends Hola was built with, for --version
+fn version() -> &'static str {
+    let version = format!(
+        "{} ({})",
+        env!("CARGO_PKG_VERSION"),
+        recognizer::backends().join(", ")
+    );
+    Box::leak(version.into_boxed_str())
+}
+
 fn main() {
-    let opts: Opts = Opts::parse();
+    let opts = Opts::from_arg_matches(&Opts::into_app().version(version()).get_matches());
+
+    // Unprivileged users go through holad, which only lets them manage their own models
+    if unsafe { libc::geteuid() } != 0 {
+        let user = user_name(unsafe { libc::getuid() }).unwrap_or_default();
+        return match opts.subcmd {
+            SubCommand::Model(o) => remote_model_command(&user, o.subcmd),
+            _ => println!("Please run this command as root"),
+        };
+    }
     if opts.user.trim().is_empty() {
         return println!("Please run this command as root");
     } else {
@@ -86,47 +336,27 @@ fn main() {
             match o.subcmd {
                 // Add a face model command
                 ModelSubCommand::Add(x) => {
-                    pb.set_message(&format!(
-                        "Adding model for user {}",
-                        style(&opts.user).bold().blue()
-                    ));
-                    sleep(Duration::from_secs(1));
                     pb.set_message("Initializing models and camera");
-                    let a = &mut App::new(base_path, &opts.user);
-                    a.start_capture();
-                    pb.set_message(
-                    "Detecting face, please make sure you are in a well lit room, CTRL+C to exit",
-                );
-                    loop {
-                        if let Some(encodings) = a.process_next_frame() {
-                            if encodings.len() >= 1 {
-                                a.push_model(
-                                    encodings.first().unwrap().as_ref().to_vec(),
-                                    x.label.clone(),
-                                );
-                                pb.set_message("Saving face encodings");
-                                match a.save_model() {
-                                    Ok(_) => {
-                                        pb.finish_with_message(&format!(
-                                            "Successfully added model for user {}",
-                                            style(&opts.user).bold().blue()
-                                        ));
-                                        return;
-                                    }
-                                    Err(_) => {
-                                        pb.finish_with_message(
-                                            &style("Error saving the models")
-                                                .bold()
-                                                .red()
-                                                .to_string(),
-                                        );
-                                    }
-                                }
-                            } else {
-                                pb.finish_with_message(
-                                    &style("Found more than one person").bold().red().to_string(),
-                                );
-                            }
+                    let a = &mut match init_app(&pb, base_path, &opts.user, false) {
+                        Some(a) => a,
+                        None => return,
+                    };
+                    match a.compatible() {
+                        Ok(true) => {}
+                        Ok(false) => return pb.finish_with_message(&incompatible_message()),
+                        Err(err) => {
+                            return pb
+                                .finish_with_message(&error_message("Error loading models", err))
+                        }
+                    }
+                    match a.enroll(&x.label, &mut || false, &mut |m: &str| pb.set_message(m)) {
+                        Ok(id) => pb.finish_with_message(&format!(
+                            "Successfully added model for user {} with ID {}",
+                            style(&opts.user).bold().blue(),
+                            style(id).bold().green()
+                        )),
+                        Err(err) => {
+                            pb.finish_with_message(&style(err.to_string()).bold().red().to_string())
                         }
                     }
                 }
@@ -134,17 +364,20 @@ fn main() {
                 // Clear all face models command
                 ModelSubCommand::Clear(_) => {
                     pb.set_message("Initializing models");
-                    let a = &mut App::new(base_path, &opts.user);
-                    if a.models().is_empty() {
+                    let a = &mut match init_app(&pb, base_path, &opts.user, true) {
+                        Some(a) => a,
+                        None => return,
+                    };
+                    if a.store().models().is_empty() {
                         return pb.finish_with_message(&format!(
                             "No models found for user {}",
                             style(&opts.user).bold().blue()
                         ));
                     }
                     pb.set_message("Clearing");
-                    a.clear_models();
+                    a.store().clear();
                     pb.set_message("Saving face encodings");
-                    match a.save_model() {
+                    match a.store().save() {
                         Ok(_) => {
                             pb.finish_with_message(&format!(
                                 "Successfully cleared models for user: {}",
@@ -160,33 +393,104 @@ fn main() {
                     }
                 }
 
+                // Purge auto-learned face models command
+                ModelSubCommand::Purge(_) => {
+                    pb.set_message("Initializing models");
+                    let a = &mut match init_app(&pb, base_path, &opts.user, true) {
+                        Some(a) => a,
+                        None => return,
+                    };
+                    pb.set_message("Purging");
+                    let count = a.store().purge_auto();
+                    if count == 0 {
+                        return pb.finish_with_message(&format!(
+                            "No auto-learned models found for user {}",
+                            style(&opts.user).bold().blue()
+                        ));
+                    }
+                    pb.set_message("Saving face encodings");
+                    match a.store().save() {
+                        Ok(_) => {
+                            pb.finish_with_message(&format!(
+                                "Successfully purged {} auto-learned models for user {}",
+                                style(count).bold().green(),
+                                style(&opts.user).bold().blue()
+                            ));
+                            return;
+                        }
+                        Err(_) => {
+                            pb.finish_with_message(
+                                &style("Error saving the models").bold().red().to_string(),
+                            );
+                        }
+                    }
+                }
+
+                // Encrypt plaintext face models command
+                ModelSubCommand::Encrypt(_) => {
+                    pb.set_message("Encrypting face models");
+                    match crypto::encrypt_model_files(base_path) {
+                        Ok(users) => {
+                            pb.finish_with_message(&format!(
+                                "Encrypted face models of {} users",
+                                style(users.len()).bold().green()
+                            ));
+                        }
+                        Err(err) => {
+                            return pb.finish_with_message(&error_message(
+                                "Error encrypting the models",
+                                err,
+                            ));
+                        }
+                    }
+                    let content =
+                        std::fs::read_to_string(base_path.join("config.toml")).unwrap_or_default();
+                    let config: Option<Config> = toml::from_str(&content).ok();
+                    if !config.map_or(false, |c| c.storage.encrypt) {
+                        println!(
+                            "Set {} in the config file to keep new models encrypted",
+                            style("[storage] encrypt = true").bold()
+                        );
+                    }
+                }
+
                 // Remove a face model command
                 ModelSubCommand::Remove(x) => {
                     pb.set_message("Initializing models");
-                    let a = &mut App::new(base_path, &opts.user);
-                    if a.models().is_empty() {
+                    let a = &mut match init_app(&pb, base_path, &opts.user, true) {
+                        Some(a) => a,
+                        None => return,
+                    };
+                    if a.store().models().is_empty() {
                         return pb.finish_with_message(&format!(
                             "No models found for user {}",
                             style(&opts.user).bold().blue()
                         ));
                     }
                     pb.set_message("Removing");
-                    let index = match a.find_model(x.id) {
-                        Some(idx) => idx,
-                        None => {
-                            return pb.finish_with_message(
-                                &style("Invalid ID").bold().red().to_string(),
-                            );
-                        }
-                    };
-                    a.remove_model(index);
+                    let ids = a.store().find(&x.model);
+                    if ids.is_empty() {
+                        return pb.finish_with_message(
+                            &style("Invalid ID or label").bold().red().to_string(),
+                        );
+                    }
+                    for id in ids.iter() {
+                        a.store().remove(*id);
+                    }
                     pb.set_message("Saving face encodings");
-                    match a.save_model() {
+                    match a.store().save() {
                         Ok(_) => {
                             pb.finish_with_message(&format!(
                                 "Successfully removed model for user {} with ID {}",
                                 style(&opts.user).bold().blue(),
-                                style(x.id).bold().green()
+                                style(
+                                    ids.iter()
+                                        .map(|id| id.to_string())
+                                        .collect::<Vec<String>>()
+                                        .join(", ")
+                                )
+                                .bold()
+                                .green()
                             ));
                             return;
                         }
@@ -201,49 +505,62 @@ fn main() {
                 // List all face model command
                 ModelSubCommand::List(_) => {
                     pb.set_message("Initializing models");
-                    let a = &mut App::new(base_path, &opts.user);
+                    let a = &mut match init_app(&pb, base_path, &opts.user, false) {
+                        Some(a) => a,
+                        None => return,
+                    };
                     pb.set_message(&format!(
                         "Fetching models for user {}",
                         style(&opts.user).bold().blue()
                     ));
-                    if a.models().is_empty() {
+                    if a.store().models().is_empty() {
                         return pb.finish_with_message(&format!(
                             "No models found for user {}",
                             style(&opts.user).bold().blue()
                         ));
                     }
                     pb.finish_and_clear();
-                    println!("Models for user {}", style(&opts.user).bold().blue());
-                    let mut table = Table::new();
-                    table.add_row(row!["ID", "Label", "Added on"]);
-                    for m in a.models().iter() {
-                        table.add_row(row![
-                            style(m.id).bold().dim().to_string(),
-                            style(&m.label).bold().to_string(),
-                            Local.timestamp(m.time, 0).to_string(),
-                        ]);
-                    }
-                    table.printstd();
+                    let models: Vec<ModelInfo> =
+                        a.store().models().iter().map(ModelInfo::from).collect();
+                    // Models of a backend that can't be loaded are still listed
+                    let compatible = a.compatible().unwrap_or(true);
+                    print_models(&opts.user, &models, compatible);
                 }
 
                 // Test against all face models command
                 ModelSubCommand::Check(_) => {
                     pb.set_message("Initializing models and camera");
-                    let a = &mut App::new(base_path, &opts.user);
-                    if a.models().is_empty() {
+                    let a = &mut match init_app(&pb, base_path, &opts.user, false) {
+                        Some(a) => a,
+                        None => return,
+                    };
+                    if a.store().models().is_empty() {
                         return pb.finish_with_message(&format!(
                             "No models found for user {}",
                             style(&opts.user).bold().blue()
                         ));
                     }
-                    a.start_capture();
+                    match a.compatible() {
+                        Ok(true) => {}
+                        Ok(false) => return pb.finish_with_message(&incompatible_message()),
+                        Err(err) => {
+                            return pb
+                                .finish_with_message(&error_message("Error loading models", err))
+                        }
+                    }
+                    if let Err(err) = a.load_models() {
+                        return pb.finish_with_message(&error_message("Error loading models", err));
+                    }
+                    if let Err(err) = a.start_capture() {
+                        return pb.finish_with_message(&error_message("Error opening camera", err));
+                    }
                     pb.set_message(
                     "Detecting face, please make sure you are in a well lit room, CTRL+C to exit",
                 );
                     let start_time = Instant::now();
                     loop {
                         if let Some(encodings) = a.process_next_frame() {
-                            if encodings.iter().any(|e| a.identify(e.clone())) {
+                            if encodings.iter().any(|e| a.identify(e)) {
                                 return pb.finish_with_message(&format!(
                                     "Identified face as {} in {:?}",
                                     style(&opts.user).bold().blue(),
@@ -260,9 +577,278 @@ fn main() {
         SubCommand::Config(_) => {
             let editor = std::env::var("EDITOR").unwrap_or("/bin/nano".to_string());
             let config_file_path = base_path.join("config.toml");
-            if let Err(err) = Exec::cmd(&editor).arg(config_file_path).join() {
+            if let Err(err) = Exec::cmd(&editor).arg(&config_file_path).join() {
                 println!("Error opening config file: {:?}", err);
             }
+
+            // Keep a signed config file valid after editing
+            if crypto::signature_path(&config_file_path).exists() {
+                if let Err(err) =
+                    crypto::sign_file(base_path, &config_file_path, crypto::CONFIG_CONTEXT)
+                {
+                    println!("Error signing config file: {}", err);
+                }
+            }
+        }
+
+        // Check ownership and permissions
+        SubCommand::Doctor(o) => {
+            let issues = security::check_permissions(base_path);
+            if issues.is_empty() {
+                return println!("{}", style("No permission problems found").bold().green());
+            }
+            let mut table = Table::new();
+            table.add_row(row!["Path", "Mode", "Problem", "Status"]);
+            for issue in issues.iter() {
+                let status = match o.fix {
+                    true => match issue.fix() {
+                        Ok(_) => style("fixed").green().to_string(),
+                        Err(err) => style(format!("error: {}", err)).red().to_string(),
+                    },
+                    false => style("not fixed").dim().to_string(),
+                };
+                table.add_row(row![
+                    style(issue.path.display()).bold().to_string(),
+                    format!("{:o}", issue.mode),
+                    style(issue.problem()).red().to_string(),
+                    status,
+                ]);
+            }
+            table.printstd();
+            if !o.fix {
+                println!(
+                    "Hola refuses to authenticate until these are fixed, run {} to fix them",
+                    style("sudo hola doctor --fix").bold()
+                );
+            }
+        }
+
+        // Reset failed attempt lockout
+        SubCommand::ResetLockout(_) => {
+            let result = StateFile::open(base_path, &opts.user).and_then(|mut f| {
+                f.state.reset_lockout();
+                f.save()
+            });
+            match result {
+                Ok(_) => println!(
+                    "Successfully reset lockout for user {}",
+                    style(&opts.user).bold().blue()
+                ),
+                Err(err) => println!("{}", error_message("Error resetting lockout", err)),
+            }
+        }
+
+        // Show recent authentication attempts
+        SubCommand::Log(o) => {
+            if !config::load(base_path).map_or(false, |c| c.log.json) {
+                println!(
+                    "Attempts are only kept with {} in the config file, otherwise use {}",
+                    style("[log] json = true").bold(),
+                    style("journalctl -g pam_hola").bold()
+                );
+            }
+            let attempts = match audit::read_attempts(base_path) {
+                Ok(a) => a,
+                Err(_) => return println!("No attempt log found"),
+            };
+            let mut attempts: Vec<&audit::Attempt> = attempts
+                .iter()
+                .rev()
+                .filter(|x| o.all_users || x.user == opts.user)
+                .filter(|x| !o.failed || !x.success)
+                .take(o.count)
+                .collect();
+            if attempts.is_empty() {
+                return println!("No attempts found");
+            }
+            attempts.reverse();
+            let mut table = Table::new();
+            table.add_row(row![
+                "Time", "User", "Service", "TTY", "Host", "Result", "Reason", "Elapsed", "Frames",
+                "Distance"
+            ]);
+            for x in attempts.iter() {
+                table.add_row(row![
+                    Local.timestamp(x.time, 0).to_string(),
+                    style(&x.user).bold().to_string(),
+                    x.service,
+                    x.tty,
+                    x.rhost,
+                    match x.success {
+                        true => style(x.result()).green().to_string(),
+                        false => style(x.result()).red().to_string(),
+                    },
+                    x.reason,
+                    format!("{}ms", x.elapsed_ms),
+                    x.frames,
+                    x.best_distance
+                        .map_or("-".to_string(), |d| format!("{:.4}", d)),
+                ]);
+            }
+            table.printstd();
+        }
+
+        // Snapshot related commands
+        SubCommand::Snapshots(o) => {
+            let snapshots = match snapshot::list(base_path) {
+                Ok(s) => s,
+                Err(err) => return println!("{}", error_message("Error reading snapshots", err)),
+            };
+            match o.subcmd {
+                SnapshotsSubCommand::List(_) => {
+                    if snapshots.is_empty() {
+                        return println!("No snapshots found");
+                    }
+                    let mut table = Table::new();
+                    table.add_row(row![
+                        "Name", "User", "Result", "Faces", "Distance", "Taken on"
+                    ]);
+                    for x in snapshots.iter() {
+                        table.add_row(row![
+                            style(&x.name).bold().dim().to_string(),
+                            style(&x.user).bold().to_string(),
+                            match x.success {
+                                true => style("success").green().to_string(),
+                                false => style("failure").red().to_string(),
+                            },
+                            x.faces,
+                            x.distance.map_or("-".to_string(), |d| format!("{:.4}", d)),
+                            Local.timestamp(x.time, 0).to_string(),
+                        ]);
+                    }
+                    table.printstd();
+                }
+                SnapshotsSubCommand::Show(x) => {
+                    let snapshot = match snapshots.iter().find(|s| s.name == x.name) {
+                        Some(s) => s,
+                        None => {
+                            return println!("{}", style("Invalid snapshot name").bold().red());
+                        }
+                    };
+                    let image_path = match snapshot::viewable_path(base_path, snapshot) {
+                        Ok(p) => p,
+                        Err(err) => {
+                            return println!("{}", error_message("Error reading snapshot", err))
+                        }
+                    };
+                    println!("{}", image_path.display());
+                    if let Err(err) = Exec::cmd("xdg-open").arg(&image_path).join() {
+                        println!("Error opening snapshot: {:?}", err);
+                    }
+                }
+                SnapshotsSubCommand::Purge(_) => {
+                    for x in snapshots.iter() {
+                        if let Err(err) = snapshot::remove(base_path, x) {
+                            return println!("{}", error_message("Error removing snapshot", err));
+                        }
+                    }
+                    println!(
+                        "Successfully removed {} snapshots",
+                        style(snapshots.len()).bold().green()
+                    );
+                }
+            }
+        }
+
+        // Measure latencies of each processing stage
+        SubCommand::Benchmark(o) => {
+            let pb = get_pb();
+            let config = match config::load(base_path) {
+                Ok(c) => c,
+                Err(err) => {
+                    return pb.finish_with_message(&error_message("Error loading config", err))
+                }
+            };
+            let mut detectors = benchmark::detectors(&config);
+            if let Some(detector) = o.detector.as_ref() {
+                detectors.retain(|(name, _)| *name == detector.as_str());
+                if detectors.is_empty() {
+                    return pb.finish_with_message(&error_message(
+                        "Unknown detector for this backend",
+                        detector,
+                    ));
+                }
+            }
+            let source: Result<Box<dyn Source>, _> = match o.dir.as_ref() {
+                Some(dir) => Recording::open(dir).map(|r| Box::new(r) as Box<dyn Source>),
+                None => Capture::open(config.video.device).map(|c| Box::new(c) as Box<dyn Source>),
+            };
+            let mut source = match source {
+                Ok(s) => s,
+                Err(err) => {
+                    return pb.finish_with_message(&error_message("Error opening frames", err))
+                }
+            };
+            let mut table = Table::new();
+            table.add_row(row![
+                "Detector",
+                "Height",
+                "Capture",
+                "Resize",
+                "Detection",
+                "Landmarks",
+                "Encoding",
+                "Faces",
+                "FPS (1 thread)"
+            ]);
+            for (name, use_cnn) in detectors {
+                pb.set_message(&format!("Loading the {} detector", name));
+                let recognizer = match benchmark::load(base_path, &config, use_cnn) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        return pb.finish_with_message(&error_message("Error loading models", err))
+                    }
+                };
+                for &height in o.heights.iter() {
+                    pb.set_message(&format!(
+                        "Processing {} frames with the {} detector at height {}",
+                        o.frames, name, height
+                    ));
+                    let stats = match benchmark::run(
+                        &recognizer,
+                        source.as_mut(),
+                        o.frames.max(1),
+                        height,
+                        use_cnn,
+                    ) {
+                        Ok(s) => s,
+                        Err(err) => {
+                            return pb.finish_with_message(&error_message("Error capturing", err))
+                        }
+                    };
+                    table.add_row(row![
+                        style(name).bold().to_string(),
+                        height,
+                        latencies(&stats.capture),
+                        latencies(&stats.resize),
+                        latencies(&stats.detection),
+                        latencies(&stats.landmarks),
+                        latencies(&stats.encoding),
+                        format!("{}/{}", stats.faces, stats.frames()),
+                        format!("{:.1}", stats.fps()),
+                    ]);
+                }
+            }
+            pb.finish_and_clear();
+            table.printstd();
+            println!("Latencies are in milliseconds, as median / 90th / 99th percentile");
+            println!(
+                "Frames are captured and processed one after the other on a single thread, \
+                 without tracking, so logins with more workers can be faster"
+            );
+        }
+
+        // Sign model and config files
+        SubCommand::Sign(o) => {
+            let pb = get_pb();
+            pb.set_message("Signing files");
+            match crypto::sign_files(base_path, o.config) {
+                Ok(count) => pb.finish_with_message(&format!(
+                    "Successfully signed {} files",
+                    style(count).bold().green()
+                )),
+                Err(err) => pb.finish_with_message(&error_message("Error signing files", err)),
+            }
         }
     }
 }
@@ -0,0 +1,254 @@
+use crate::audit;
+use crate::bus::{self, AuthError, Holad};
+use crate::config::{self, Config};
+use crate::crypto;
+use crate::daemon::{self, Event, Request, Response};
+use crate::helper::write_atomic;
+use crate::state::StateFile;
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use zbus::{dbus_interface, Connection, DBusError, MessageHeader};
+
+pub const SERVICE_NAME: &str = "org.hola";
+pub const MANAGER_PATH: &str = "/org/hola/Manager";
+const MANAGER_INTERFACE: &str = "org.hola.Manager";
+
+// How long to wait before reconnecting to holad for events
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(DBusError, Debug)]
+#[dbus_error(prefix = "org.hola.Error")]
+pub enum Error {
+    ZBus(zbus::Error),
+    PermissionDenied(String),
+    Failed(String),
+}
+
+impl From<AuthError> for Error {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Internal(err) => Error::Failed(err),
+            AuthError::PermissionDenied(err) => Error::PermissionDenied(err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn failed<E: ToString>(err: E) -> Error {
+    Error::Failed(err.to_string())
+}
+
+fn emit<B: serde::Serialize + zvariant::Type>(connection: &Connection, signal: &str, body: &B) {
+    connection
+        .emit_signal(None, MANAGER_PATH, MANAGER_INTERFACE, signal, body)
+        .ok();
+}
+
+// Forwards holad's authentication events as signals, reconnecting when holad restarts
+pub fn forward_events(connection: Connection) {
+    loop {
+        let result = daemon::subscribe(&mut |event| match event {
+            Event::Started { user } => emit(&connection, "AuthenticationStarted", &user),
+            Event::Finished { user, result } => emit(
+                &connection,
+                "AuthenticationFinished",
+                &(
+                    user,
+                    result.identified,
+                    result.label.unwrap_or_default(),
+                    result.reason,
+                ),
+            ),
+        });
+        if let Err(err) = result {
+            eprintln!("Error receiving events from holad: {}", err);
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+// Management and status API for desktop components, see the README for details
+pub struct Manager {
+    connection: Connection,
+    base_path: PathBuf,
+}
+
+impl Manager {
+    pub fn new<P: AsRef<Path>>(connection: Connection, base_path: P) -> Self {
+        Self {
+            connection,
+            base_path: base_path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[dbus_interface(name = "org.hola.Manager")]
+impl Manager {
+    // Contents of config.toml, which is only readable by root. Root only.
+    fn get_config(&self, #[zbus(header)] header: MessageHeader<'_>) -> Result<String> {
+        bus::authorize_root(&self.connection, &header)?;
+        read_to_string(self.base_path.join("config.toml")).map_err(failed)
+    }
+
+    // Replaces config.toml, keeping it signed when it was. Root only.
+    fn set_config(&self, #[zbus(header)] header: MessageHeader<'_>, content: &str) -> Result<()> {
+        bus::authorize_root(&self.connection, &header)?;
+        toml::from_str::<Config>(content)
+            .map_err(|e| failed(format!("Failed to parse config file: {}", e)))?;
+        let path = self.base_path.join("config.toml");
+        write_atomic(&path, content.as_bytes()).map_err(failed)?;
+        if crypto::signature_path(&path).exists() {
+            crypto::sign_file(&self.base_path, &path, crypto::CONFIG_CONTEXT).map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    // Models of the user as ID, label, time added and whether it was auto-learned
+    fn list_models(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        user: &str,
+    ) -> Result<Vec<(u32, String, i64, bool)>> {
+        let user = bus::authorize(&self.connection, &header, user)?;
+        match bus::request(&Holad, Request::List { user }, Error::Failed)? {
+            Response::Models { models, .. } => Ok(models
+                .into_iter()
+                .map(|m| (m.id as u32, m.label, m.time, m.auto))
+                .collect()),
+            _ => Err(failed("Unexpected response from holad")),
+        }
+    }
+
+    // Enrolls a new model in the background, reporting through EnrollStatus signals. Root
+    // only, anyone at the user's unlocked session could add their own face.
+    fn enroll_model(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        user: &str,
+        label: &str,
+    ) -> Result<()> {
+        bus::authorize_root(&self.connection, &header)?;
+        let user = bus::authorize(&self.connection, &header, user)?;
+        let request = Request::Enroll {
+            user: user.clone(),
+            label: label.to_string(),
+        };
+        let connection = self.connection.clone();
+        thread::spawn(move || {
+            let status = |message: &str, done: bool, success: bool| {
+                emit(
+                    &connection,
+                    "EnrollStatus",
+                    &(&user, message, done, success),
+                );
+            };
+            let response = daemon::request(&request, &mut |m: &str| status(m, false, false));
+            match response {
+                Ok(Response::Enrolled(id)) => {
+                    status(&format!("Added model with ID {}", id), true, true)
+                }
+                Ok(Response::Error(err)) => status(&err, true, false),
+                Ok(_) => status("Unexpected response from holad", true, false),
+                Err(err) => status(&err.to_string(), true, false),
+            }
+        });
+        Ok(())
+    }
+
+    // Removes models by ID or label, returning the removed IDs
+    fn remove_model(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        user: &str,
+        model: &str,
+    ) -> Result<Vec<u32>> {
+        let user = bus::authorize(&self.connection, &header, user)?;
+        let model = model.to_string();
+        let request = Request::Remove { user, model };
+        match bus::request(&Holad, request, Error::Failed)? {
+            Response::Removed(ids) => Ok(ids.into_iter().map(|id| id as u32).collect()),
+            _ => Err(failed("Unexpected response from holad")),
+        }
+    }
+
+    // Most recent attempts of the user, oldest first, as time, service, tty, remote host,
+    // success, reason, elapsed milliseconds, frames and best distance, -1 when unknown
+    fn get_attempts(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        user: &str,
+        count: u32,
+    ) -> Result<Vec<(i64, String, String, String, bool, String, u64, u32, f64)>> {
+        let user = bus::authorize(&self.connection, &header, user)?;
+        let attempts = audit::read_attempts(&self.base_path).unwrap_or_default();
+        let mut attempts: Vec<_> = attempts
+            .into_iter()
+            .rev()
+            .filter(|x| x.user == user)
+            .take(count as usize)
+            .map(|x| {
+                (
+                    x.time,
+                    x.service,
+                    x.tty,
+                    x.rhost,
+                    x.success,
+                    x.reason,
+                    x.elapsed_ms,
+                    x.frames,
+                    x.best_distance.unwrap_or(-1.0),
+                )
+            })
+            .collect();
+        attempts.reverse();
+        Ok(attempts)
+    }
+
+    // Failed attempts inside the lockout window, and seconds left until the lockout ends
+    fn get_lockout(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        user: &str,
+    ) -> Result<(u32, i64)> {
+        let user = bus::authorize(&self.connection, &header, user)?;
+        let file = StateFile::open(&self.base_path, &user).map_err(failed)?;
+        let config = config::load(&self.base_path).map_err(failed)?;
+        let window = chrono::Local::now().timestamp() - config.lockout.window;
+        let failures = file.state.failures.iter().filter(|&&t| t > window).count();
+        Ok((failures as u32, file.state.locked_for().unwrap_or(0)))
+    }
+
+    // Clears the user's failed attempts and lockout. Root only.
+    fn reset_lockout(&self, #[zbus(header)] header: MessageHeader<'_>, user: &str) -> Result<()> {
+        bus::authorize_root(&self.connection, &header)?;
+        let mut file = StateFile::open(&self.base_path, user).map_err(failed)?;
+        file.state.reset_lockout();
+        file.save().map_err(failed)
+    }
+
+    #[dbus_interface(signal)]
+    fn authentication_started(&self, user: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn authentication_finished(
+        &self,
+        user: &str,
+        success: bool,
+        label: &str,
+        reason: &str,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn enroll_status(
+        &self,
+        user: &str,
+        message: &str,
+        done: bool,
+        success: bool,
+    ) -> zbus::Result<()>;
+}
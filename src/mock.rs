@@ -0,0 +1,145 @@
+use crate::backend::{Detector, Encoder, LandmarkModel, Landmarks, Rect};
+use crate::capture::Source;
+use image::{Rgb, RgbImage};
+use std::error::Error;
+
+// Deterministic stand-ins for the dlib models and the camera, to exercise the decision
+// logic without model files or hardware. A "face" is a block of non-black pixels, and its
+// encoding is derived from the block's average colour, so frames drawn with the same colour
+// match and frames drawn with clearly different colours don't.
+
+// Pixels with any channel above this are part of a face
+const THRESHOLD: u8 = 16;
+
+const ENCODING_DIMENSION: usize = 128;
+
+fn lit(image: &RgbImage, x: u32, y: u32) -> bool {
+    image.get_pixel(x, y).0.iter().any(|&c| c > THRESHOLD)
+}
+
+// Finds each run of columns containing lit pixels as one face, bounded by the first and last
+// lit rows in it
+pub struct MockDetector;
+
+impl Detector for MockDetector {
+    fn detect(&self, image: &RgbImage) -> Vec<Rect> {
+        let mut faces = Vec::new();
+        let mut start: Option<u32> = None;
+        for x in 0..=image.width() {
+            let found = x < image.width() && (0..image.height()).any(|y| lit(image, x, y));
+            match (found, start) {
+                (true, None) => start = Some(x),
+                (false, Some(left)) => {
+                    let rows: Vec<u32> = (0..image.height())
+                        .filter(|&y| (left..x).any(|column| lit(image, column, y)))
+                        .collect();
+                    faces.push((
+                        left as i64,
+                        rows[0] as i64,
+                        x as i64 - 1,
+                        rows[rows.len() - 1] as i64,
+                    ));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        faces
+    }
+}
+
+// Places five points at fixed positions inside the face box
+pub struct MockLandmarks;
+
+impl LandmarkModel for MockLandmarks {
+    fn landmarks(&self, _image: &RgbImage, faces: &[Rect]) -> Vec<Landmarks> {
+        faces
+            .iter()
+            .map(|&(left, top, right, bottom)| {
+                let (w, h) = (right - left, bottom - top);
+                Landmarks {
+                    face: (left, top, right, bottom),
+                    points: vec![
+                        (left + w / 4, top + h / 3),
+                        (left + w / 3, top + h / 3),
+                        (right - w / 3, top + h / 3),
+                        (right - w / 4, top + h / 3),
+                        (left + w / 2, top + h * 2 / 3),
+                    ],
+                    native: None,
+                }
+            })
+            .collect()
+    }
+}
+
+// Spreads the face's average colour over the encoding, scaled so the distance between two
+// encodings is roughly the distance between their colours with channels in 0-1
+pub struct MockEncoder;
+
+impl Encoder for MockEncoder {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn dimension(&self) -> usize {
+        ENCODING_DIMENSION
+    }
+
+    fn encode(&self, image: &RgbImage, landmarks: &[Landmarks]) -> Vec<Vec<f64>> {
+        let scale = (ENCODING_DIMENSION as f64 / 3.0).sqrt();
+        landmarks
+            .iter()
+            .map(|l| {
+                let (left, top, right, bottom) = l.face;
+                let mut sum = [0f64; 3];
+                let mut count = 0f64;
+                for y in top.max(0)..=bottom.min(image.height() as i64 - 1) {
+                    for x in left.max(0)..=right.min(image.width() as i64 - 1) {
+                        let pixel = image.get_pixel(x as u32, y as u32);
+                        for (s, &c) in sum.iter_mut().zip(pixel.0.iter()) {
+                            *s += c as f64;
+                        }
+                        count += 1.0;
+                    }
+                }
+                (0..ENCODING_DIMENSION)
+                    .map(|i| sum[i % 3] / count.max(1.0) / 255.0 / scale)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// A black frame with a single face drawn in the given colour
+pub fn face_frame(colour: [u8; 3]) -> RgbImage {
+    RgbImage::from_fn(64, 48, |x, y| {
+        match (16..48).contains(&x) && (8..40).contains(&y) {
+            true => Rgb(colour),
+            false => Rgb([0, 0, 0]),
+        }
+    })
+}
+
+// Plays back a fixed list of frames in a loop
+pub struct MockSource {
+    frames: Vec<RgbImage>,
+    next: usize,
+}
+
+impl MockSource {
+    pub fn new(frames: Vec<RgbImage>) -> Self {
+        Self { frames, next: 0 }
+    }
+}
+
+impl Source for MockSource {
+    fn next_frame(&mut self) -> Result<RgbImage, Box<dyn Error>> {
+        if self.frames.is_empty() {
+            return Err("No frames to play back".into());
+        }
+        let frame = self.frames[self.next % self.frames.len()].clone();
+        self.next += 1;
+        Ok(frame)
+    }
+}
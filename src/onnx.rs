@@ -0,0 +1,490 @@
+use crate::audit;
+use crate::backend::{Detector, Encoder, LandmarkModel, Landmarks, Rect};
+use crate::config::Onnx;
+use crate::helper::hash_file;
+use crate::recognizer::Recognizer;
+use image::{imageops, RgbImage};
+#[cfg(feature = "onnx")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "onnx")]
+use onnxruntime::{
+    environment::Environment, ndarray::Array4, session::Session, tensor::OrtOwnedTensor,
+    GraphOptimizationLevel, LoggingLevel,
+};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+#[cfg(all(feature = "tract", not(feature = "onnx")))]
+use tract_onnx::prelude::*;
+
+// Dimension of the embeddings of ArcFace recognizers
+pub const ENCODING_DIMENSION: usize = 512;
+
+// SCRFD takes a square input, with two anchors per location at each stride. The outputs are
+// the scores of all strides, then the boxes, then the keypoints.
+const DETECTOR_SIZE: u32 = 640;
+const STRIDES: [u32; 3] = [8, 16, 32];
+const ANCHORS: usize = 2;
+const SCORE_THRESHOLD: f32 = 0.5;
+const NMS_THRESHOLD: f32 = 0.4;
+
+// ArcFace takes a 112x112 face aligned so the eyes, nose and mouth corners land here
+const FACE_SIZE: u32 = 112;
+const FACE_TEMPLATE: [(f32, f32); 5] = [
+    (38.2946, 51.6963),
+    (73.5318, 51.5014),
+    (56.0252, 71.7366),
+    (41.5493, 92.3655),
+    (70.7299, 92.2041),
+];
+
+// ONNX Runtime allows a single environment per process
+#[cfg(feature = "onnx")]
+static ENVIRONMENT: OnceCell<Environment> = OnceCell::new();
+
+#[cfg(feature = "onnx")]
+fn environment() -> Result<&'static Environment, Box<dyn Error>> {
+    Ok(ENVIRONMENT.get_or_try_init(|| {
+        Environment::builder()
+            .with_name("hola")
+            .with_log_level(LoggingLevel::Warning)
+            .build()
+    })?)
+}
+
+// Path of an ONNX model file, failing with a hint when it's missing
+fn model_path<P: AsRef<Path>>(base_path: P, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = base_path.as_ref().join("onnx_models").join(name);
+    if !path.exists() {
+        return Err(format!(
+            "Missing ONNX model file {}, see the README for where to get it",
+            path.display()
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+// Input of a network, a single image in NCHW layout
+struct Input {
+    shape: [usize; 4],
+    data: Vec<f32>,
+}
+
+impl Input {
+    fn new(channels: usize, height: usize, width: usize, fill: f32) -> Self {
+        Self {
+            shape: [1, channels, height, width],
+            data: vec![fill; channels * height * width],
+        }
+    }
+
+    fn set(&mut self, c: usize, y: usize, x: usize, value: f32) {
+        let [_, _, height, width] = self.shape;
+        self.data[(c * height + y) * width + x] = value;
+    }
+}
+
+// An ONNX model run by ONNX Runtime
+#[cfg(feature = "onnx")]
+struct Network(Mutex<Session<'static>>);
+
+#[cfg(feature = "onnx")]
+impl Network {
+    fn load(path: PathBuf, _shape: [usize; 4], threads: i16) -> Result<Self, Box<dyn Error>> {
+        let session = environment()?
+            .new_session_builder()?
+            .with_optimization_level(GraphOptimizationLevel::Basic)?
+            .with_number_threads(threads)?
+            .with_model_from_file(path)?;
+        Ok(Self(Mutex::new(session)))
+    }
+
+    // Runs the model, copying the outputs out of the session
+    fn run(&self, input: Input) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let [n, c, h, w] = input.shape;
+        let array = Array4::from_shape_vec((n, c, h, w), input.data)?;
+        let mut session = self.0.lock().unwrap();
+        let outputs: Vec<OrtOwnedTensor<f32, _>> = session.run(vec![array])?;
+        Ok(outputs
+            .iter()
+            .map(|o| o.iter().copied().collect())
+            .collect())
+    }
+}
+
+// An ONNX model run by tract, optimized for a fixed input shape
+#[cfg(all(feature = "tract", not(feature = "onnx")))]
+struct Network(SimplePlan<TypedFact, Box<dyn TypedOp>, TypedModel>);
+
+#[cfg(all(feature = "tract", not(feature = "onnx")))]
+impl Network {
+    fn load(path: PathBuf, shape: [usize; 4], _threads: i16) -> Result<Self, Box<dyn Error>> {
+        let fact = InferenceFact::dt_shape(
+            f32::datum_type(),
+            tvec!(shape[0], shape[1], shape[2], shape[3]),
+        );
+        let plan = tract_onnx::onnx()
+            .model_for_path(path)?
+            .with_input_fact(0, fact)?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(Self(plan))
+    }
+
+    fn run(&self, input: Input) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let tensor = Tensor::from_shape(&input.shape, &input.data)?;
+        let mut outputs = Vec::new();
+        for output in self.0.run(tvec!(tensor))?.iter() {
+            outputs.push(output.as_slice::<f32>()?.to_vec());
+        }
+        Ok(outputs)
+    }
+}
+
+// Tags the hash of the recognizer network, so its embeddings never mix with dlib encodings
+pub fn encoder_hash<P: AsRef<Path>>(base_path: P, config: &Onnx) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "onnx:{}",
+        hash_file(model_path(base_path, &config.recognizer)?)?
+    ))
+}
+
+// Loads the SCRFD detector, which also provides the landmarks, and the ArcFace recognizer
+pub fn load<P: AsRef<Path>>(base_path: P, config: &Onnx) -> Result<Recognizer, Box<dyn Error>> {
+    let side = DETECTOR_SIZE as usize;
+    let scrfd = Arc::new(Scrfd {
+        network: Network::load(
+            model_path(&base_path, &config.detector)?,
+            [1, 3, side, side],
+            config.threads,
+        )?,
+        last: Mutex::new(Vec::new()),
+    });
+    let side = FACE_SIZE as usize;
+    let arcface = ArcFace {
+        network: Network::load(
+            model_path(&base_path, &config.recognizer)?,
+            [1, 3, side, side],
+            config.threads,
+        )?,
+        name: encoder_hash(&base_path, config)?,
+    };
+    Ok(Recognizer::new(
+        Box::new(ScrfdDetector(scrfd.clone())),
+        Box::new(ScrfdLandmarks(scrfd)),
+        Box::new(arcface),
+    ))
+}
+
+// Intersection over union of two boxes as left, top, right and bottom
+fn iou(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    let width = (a[2].min(b[2]) - a[0].max(b[0])).max(0.0);
+    let height = (a[3].min(b[3]) - a[1].max(b[1])).max(0.0);
+    let intersection = width * height;
+    let area = |r: &[f32; 4]| (r[2] - r[0]) * (r[3] - r[1]);
+    intersection / (area(a) + area(b) - intersection).max(f32::EPSILON)
+}
+
+fn to_array(r: &Rect) -> [f32; 4] {
+    [r.0 as f32, r.1 as f32, r.2 as f32, r.3 as f32]
+}
+
+// A face found by SCRFD, with its five keypoints
+type Face = (Rect, Vec<(i64, i64)>);
+
+struct Scrfd {
+    network: Network,
+    // Faces found in the last frame, their keypoints are handed out as landmarks
+    last: Mutex<Vec<Face>>,
+}
+
+impl Scrfd {
+    fn detect(&self, image: &RgbImage) -> Result<Vec<Face>, Box<dyn Error>> {
+        // Letterbox the frame into the top left of the input
+        let size = DETECTOR_SIZE as f32;
+        let scale = (size / image.width() as f32).min(size / image.height() as f32);
+        let width = ((image.width() as f32 * scale) as u32).clamp(1, DETECTOR_SIZE);
+        let height = ((image.height() as f32 * scale) as u32).clamp(1, DETECTOR_SIZE);
+        let resized = imageops::resize(image, width, height, imageops::FilterType::Triangle);
+        let side = DETECTOR_SIZE as usize;
+        let mut input = Input::new(3, side, side, -127.5 / 128.0);
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for c in 0..3 {
+                input.set(c, y as usize, x as usize, (pixel[c] as f32 - 127.5) / 128.0);
+            }
+        }
+
+        decode(&self.network.run(input)?, scale)
+    }
+}
+
+// Decodes the outputs of SCRFD into faces on a frame that was scaled by scale
+fn decode(outputs: &[Vec<f32>], scale: f32) -> Result<Vec<Face>, Box<dyn Error>> {
+    if outputs.len() < STRIDES.len() * 3 {
+        return Err("The detector must be an SCRFD model with keypoints".into());
+    }
+    let mut candidates: Vec<(f32, [f32; 4], [(f32, f32); 5])> = Vec::new();
+    for (i, &stride) in STRIDES.iter().enumerate() {
+        let scores = &outputs[i];
+        let boxes = &outputs[i + STRIDES.len()];
+        let points = &outputs[i + STRIDES.len() * 2];
+        let columns = (DETECTOR_SIZE / stride) as usize;
+        if boxes.len() < scores.len() * 4 || points.len() < scores.len() * 10 {
+            return Err("Unexpected output shape of the SCRFD model".into());
+        }
+        let s = stride as f32;
+        for (k, &score) in scores.iter().enumerate() {
+            // NaN would never compare below the threshold
+            if !score.is_finite() || score < SCORE_THRESHOLD {
+                continue;
+            }
+            let cell = k / ANCHORS;
+            let cx = (cell % columns) as f32 * s;
+            let cy = (cell / columns) as f32 * s;
+            let b = &boxes[k * 4..k * 4 + 4];
+            let rect = [cx - b[0] * s, cy - b[1] * s, cx + b[2] * s, cy + b[3] * s];
+            if rect.iter().any(|v| !v.is_finite()) {
+                continue;
+            }
+            let mut keypoints = [(0.0, 0.0); 5];
+            for (j, point) in keypoints.iter_mut().enumerate() {
+                let p = &points[k * 10 + j * 2..k * 10 + j * 2 + 2];
+                *point = (cx + p[0] * s, cy + p[1] * s);
+            }
+            candidates.push((score, rect, keypoints));
+        }
+    }
+
+    // Keep the best scoring of overlapping boxes, mapped back onto the frame
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut kept: Vec<([f32; 4], [(f32, f32); 5])> = Vec::new();
+    for (_, rect, keypoints) in candidates {
+        if kept.iter().all(|(k, _)| iou(k, &rect) < NMS_THRESHOLD) {
+            kept.push((rect, keypoints));
+        }
+    }
+    let unscale = |v: f32| (v / scale).round() as i64;
+    Ok(kept
+        .iter()
+        .map(|(r, keypoints)| {
+            (
+                (unscale(r[0]), unscale(r[1]), unscale(r[2]), unscale(r[3])),
+                keypoints
+                    .iter()
+                    .map(|&(x, y)| (unscale(x), unscale(y)))
+                    .collect(),
+            )
+        })
+        .collect())
+}
+
+pub struct ScrfdDetector(Arc<Scrfd>);
+
+impl Detector for ScrfdDetector {
+    fn detect(&self, image: &RgbImage) -> Vec<Rect> {
+        let faces = self.0.detect(image).unwrap_or_else(|err| {
+            audit::log(&format!("SCRFD detection failed: {}", err));
+            Vec::new()
+        });
+        let rects = faces.iter().map(|(r, _)| *r).collect();
+        *self.0.last.lock().unwrap() = faces;
+        rects
+    }
+}
+
+// Hands out the keypoints SCRFD found along with the faces
+pub struct ScrfdLandmarks(Arc<Scrfd>);
+
+impl LandmarkModel for ScrfdLandmarks {
+    fn landmarks(&self, image: &RgbImage, faces: &[Rect]) -> Vec<Landmarks> {
+        // Faces that weren't just detected need a fresh detection to get their keypoints
+        let mut last = self.0.last.lock().unwrap();
+        if faces.iter().any(|f| !last.iter().any(|(r, _)| r == f)) {
+            *last = self.0.detect(image).unwrap_or_default();
+        }
+        faces
+            .iter()
+            .map(|face| {
+                let closest = last
+                    .iter()
+                    .map(|(r, points)| (iou(&to_array(r), &to_array(face)), points))
+                    .filter(|(overlap, _)| *overlap > NMS_THRESHOLD)
+                    .max_by(|a, b| a.0.total_cmp(&b.0));
+                Landmarks {
+                    face: *face,
+                    points: closest.map(|(_, p)| p.clone()).unwrap_or_default(),
+                    native: None,
+                }
+            })
+            .collect()
+    }
+}
+
+// Similarity transform, as the rotation and scale terms a and b and a translation, mapping
+// the template onto the landmarks in the least squares sense
+fn similarity(points: &[(i64, i64)]) -> (f32, f32, f32, f32) {
+    let n = FACE_TEMPLATE.len() as f32;
+    let mean = |v: &mut dyn Iterator<Item = (f32, f32)>| {
+        let (x, y) = v.fold((0.0, 0.0), |s, p| (s.0 + p.0, s.1 + p.1));
+        (x / n, y / n)
+    };
+    let points: Vec<(f32, f32)> = points.iter().map(|&(x, y)| (x as f32, y as f32)).collect();
+    let (tx, ty) = mean(&mut FACE_TEMPLATE.iter().copied());
+    let (px, py) = mean(&mut points.iter().copied());
+    let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+    for (t, p) in FACE_TEMPLATE.iter().zip(points.iter()) {
+        let (ux, uy) = (t.0 - tx, t.1 - ty);
+        let (vx, vy) = (p.0 - px, p.1 - py);
+        dot += ux * vx + uy * vy;
+        cross += ux * vy - uy * vx;
+        norm += ux * ux + uy * uy;
+    }
+    let (a, b) = (dot / norm, cross / norm);
+    (a, b, px - (a * tx - b * ty), py - (b * tx + a * ty))
+}
+
+// Bilinear sample of a channel, black outside the image
+fn sample(image: &RgbImage, x: f32, y: f32, c: usize) -> f32 {
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    if x < 0.0 || y < 0.0 || x > w - 1.0 || y > h - 1.0 {
+        return 0.0;
+    }
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = (
+        (x0 + 1).min(image.width() - 1),
+        (y0 + 1).min(image.height() - 1),
+    );
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let value = |x, y| image.get_pixel(x, y)[c] as f32;
+    let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+    let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+// ArcFace recognizer, taking faces aligned by their five SCRFD keypoints
+pub struct ArcFace {
+    network: Network,
+    name: String,
+}
+
+impl ArcFace {
+    fn encode_face(
+        &self,
+        image: &RgbImage,
+        points: &[(i64, i64)],
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let (a, b, tx, ty) = similarity(points);
+        let side = FACE_SIZE as usize;
+        let mut input = Input::new(3, side, side, 0.0);
+        for v in 0..side {
+            for u in 0..side {
+                let (uf, vf) = (u as f32, v as f32);
+                let (x, y) = (a * uf - b * vf + tx, b * uf + a * vf + ty);
+                for c in 0..3 {
+                    input.set(c, v, u, (sample(image, x, y, c) - 127.5) / 127.5);
+                }
+            }
+        }
+        let embedding = self
+            .network
+            .run(input)?
+            .into_iter()
+            .next()
+            .ok_or("The recognizer has no output")?;
+        let norm = embedding
+            .iter()
+            .map(|x| x * x)
+            .sum::<f32>()
+            .sqrt()
+            .max(f32::EPSILON);
+        Ok(embedding.iter().map(|&x| (x / norm) as f64).collect())
+    }
+}
+
+impl Encoder for ArcFace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dimension(&self) -> usize {
+        ENCODING_DIMENSION
+    }
+
+    fn encode(&self, image: &RgbImage, landmarks: &[Landmarks]) -> Vec<Vec<f64>> {
+        landmarks
+            .iter()
+            .filter(|l| l.points.len() == FACE_TEMPLATE.len())
+            .filter_map(|l| match self.encode_face(image, &l.points) {
+                Ok(encoding) => Some(encoding),
+                Err(err) => {
+                    audit::log(&format!("ArcFace encoding failed: {}", err));
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Empty SCRFD outputs, the scores, boxes and keypoints of each stride
+    fn outputs() -> Vec<Vec<f32>> {
+        let sizes: Vec<usize> = STRIDES
+            .iter()
+            .map(|&s| (DETECTOR_SIZE / s).pow(2) as usize * ANCHORS)
+            .collect();
+        let mut outputs: Vec<Vec<f32>> = sizes.iter().map(|&n| vec![0.0; n]).collect();
+        outputs.extend(sizes.iter().map(|&n| vec![0.0; n * 4]));
+        outputs.extend(sizes.iter().map(|&n| vec![0.0; n * 10]));
+        outputs
+    }
+
+    // Sets the score and box distances of an anchor at the given stride
+    fn face(outputs: &mut [Vec<f32>], stride: usize, k: usize, score: f32, distances: [f32; 4]) {
+        outputs[stride][k] = score;
+        outputs[stride + STRIDES.len()][k * 4..k * 4 + 4].copy_from_slice(&distances);
+    }
+
+    #[test]
+    fn decodes_faces_and_suppresses_overlaps() {
+        let mut outputs = outputs();
+        // Both anchors of the cell at (80, 80) on stride 8, the weaker box overlaps
+        face(&mut outputs, 0, 1620, 0.9, [2.0, 2.0, 2.0, 2.0]);
+        face(&mut outputs, 0, 1621, 0.6, [2.0, 2.0, 2.0, 1.5]);
+        // The cell at (96, 64) on stride 32, only partly overlapping
+        face(&mut outputs, 2, 86, 0.7, [1.0, 1.0, 1.0, 1.0]);
+        // Below the threshold
+        face(&mut outputs, 1, 10, 0.4, [1.0, 1.0, 1.0, 1.0]);
+
+        let faces = decode(&outputs, 2.0).unwrap();
+        assert_eq!(faces.len(), 2);
+        assert_eq!(faces[0].0, (32, 32, 48, 48));
+        assert_eq!(faces[0].1, vec![(40, 40); 5]);
+        assert_eq!(faces[1].0, (32, 16, 64, 48));
+    }
+
+    #[test]
+    fn rejects_non_finite_outputs() {
+        let mut outputs = outputs();
+        face(&mut outputs, 0, 1620, f32::NAN, [2.0, 2.0, 2.0, 2.0]);
+        face(&mut outputs, 0, 1700, f32::INFINITY, [2.0, 2.0, 2.0, 2.0]);
+        face(&mut outputs, 1, 10, 0.9, [f32::NAN, 1.0, 1.0, 1.0]);
+        assert!(decode(&outputs, 1.0).unwrap().is_empty());
+
+        // Missing keypoint outputs
+        assert!(decode(&outputs[..STRIDES.len() * 2], 1.0).is_err());
+    }
+
+    #[test]
+    fn measures_overlap() {
+        let a = [0.0, 0.0, 10.0, 10.0];
+        assert_eq!(iou(&a, &a), 1.0);
+        assert_eq!(iou(&a, &[10.0, 10.0, 20.0, 20.0]), 0.0);
+        assert!((iou(&a, &[5.0, 0.0, 15.0, 10.0]) - 1.0 / 3.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::app::{App, ScanResult};
+use crate::audit::{self, Attempt};
+use crate::auth::{self, Messages, Outcome};
+use crate::config::{self, Config};
+use crate::state::StateFile;
+use crate::tty::KeyWatcher;
+use crate::{crypto, daemon, security};
+use pamsm::{pam_module, Pam, PamError, PamFlag, PamLibExt, PamMsgStyle, PamServiceModule};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    fs::read,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// PAM handle data set when the face was identified, telling setcred the stack succeeded
+// without a password
+const FACE_LOGIN: &str = "pam_hola_face_login";
+
+// PAM handle data set when the face wasn't identified and the stack went on to the
+// modules after Hola, usually the password
+const FELL_BACK: &str = "pam_hola_fell_back";
+
+// PAM handle data set by the first setcred establishing credentials
+const CREDENTIALS_SET: &str = "pam_hola_credentials_set";
+
+struct PamTime;
+
+impl PamServiceModule for PamTime {
+    fn authenticate(pamh: Pam, _flags: PamFlag, args: Vec<String>) -> PamError {
+        authenticate(pamh, args)
+    }
+
+    // The session is opened after authentication, scanning again would only delay it
+    fn open_session(_pamh: Pam, _flags: PamFlag, _args: Vec<String>) -> PamError {
+        PamError::IGNORE
+    }
+
+    fn close_session(_pamh: Pam, _flags: PamFlag, _args: Vec<String>) -> PamError {
+        PamError::SUCCESS
+    }
+
+    // Applications only establish credentials once the whole stack succeeded, so unless
+    // the face was identified, a module after Hola accepted the password. Linux-PAM clears
+    // PAM_AUTHTOK when pam_authenticate returns, so the handle data tells that Hola fell
+    // back in this handle.
+    fn setcred(pamh: Pam, flags: PamFlag, _args: Vec<String>) -> PamError {
+        let face_login = pamh.retrieve_bytes(FACE_LOGIN).is_ok();
+        let fell_back = pamh.retrieve_bytes(FELL_BACK).is_ok();
+        if !password_used(flags as i32, face_login, fell_back) {
+            return match face_login {
+                true => PamError::SUCCESS,
+                false => PamError::IGNORE,
+            };
+        }
+        if pamh.retrieve_bytes(CREDENTIALS_SET).is_ok() {
+            return PamError::IGNORE;
+        }
+        pamh.send_bytes(CREDENTIALS_SET, vec![1], None).ok();
+        if let Ok(Some(user)) = pamh.get_user(None) {
+            if let Ok(mut f) = StateFile::open(base_path(), &user.to_string_lossy()) {
+                f.state.record_password();
+                f.save().ok();
+            }
+        }
+        PamError::IGNORE
+    }
+}
+
+// Whether setcred is establishing credentials after a password was accepted. Refreshing or
+// deleting credentials says nothing about how the user authenticated. The flags may be
+// combined with PAM_SILENT.
+fn password_used(flags: i32, face_login: bool, fell_back: bool) -> bool {
+    let establish = flags & PamFlag::PAM_ESTABLISH_CRED as i32 != 0;
+    establish && fell_back && !face_login
+}
+
+fn base_path() -> &'static Path {
+    Path::new("/lib/security/pam_hola")
+}
+
+fn authenticate(pamh: Pam, args: Vec<String>) -> PamError {
+    let base_path = base_path();
+    let user = match pamh.get_user(None) {
+        Ok(Some(u)) => {
+            if let Ok(u_str) = u.to_str() {
+                u_str
+            } else {
+                return PamError::USER_UNKNOWN;
+            }
+        }
+        Ok(None) => return PamError::USER_UNKNOWN,
+        Err(e) => return e,
+    };
+
+    // Entered passwords are recorded by setcred, a second instance after the password
+    // module used to do it on the auth path, where it also ran after a wrong password
+    if args.iter().any(|x| x == "password_ok") {
+        return PamError::IGNORE;
+    }
+
+    // Every attempt is logged with its outcome
+    let mut attempt = Attempt::new(
+        user,
+        item(pamh.get_service()),
+        item(pamh.get_tty()),
+        item(pamh.get_rhost()),
+    );
+    let start_time = Instant::now();
+    let result = authenticate_user(&pamh, &args, base_path, user, &mut attempt);
+    if !matches!(result, PamError::SUCCESS) {
+        pamh.send_bytes(FELL_BACK, vec![1], None).ok();
+    }
+    attempt.finish(matches!(result, PamError::SUCCESS), start_time.elapsed());
+    let json = config::load(base_path).map_or(false, |c| c.log.json);
+    audit::record(base_path, &attempt, json);
+    result
+}
+
+// Converts an optional PAM item into a string, empty when unset
+fn item(value: Result<Option<&CStr>, PamError>) -> String {
+    match value {
+        Ok(Some(v)) => v.to_string_lossy().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn authenticate_user(
+    pamh: &Pam,
+    args: &[String],
+    base_path: &Path,
+    user: &str,
+    attempt: &mut Attempt,
+) -> PamError {
+    // Refuse to trust files that someone other than root could have modified
+    let issues = security::check_permissions(base_path);
+    if !issues.is_empty() {
+        for issue in issues.iter() {
+            audit::log(&format!(
+                "refusing to authenticate {}: {} is {}",
+                user,
+                issue.path.display(),
+                issue.problem()
+            ));
+        }
+        attempt.reason = "insecure_permissions".to_string();
+        return PamError::AUTHINFO_UNAVAIL;
+    }
+
+    // With the verify_config argument an unsigned config file is refused
+    let config_file_path = base_path.join("config.toml");
+    let content = match read(&config_file_path) {
+        Ok(content) => content,
+        Err(err) => {
+            audit::log(&format!("refusing to authenticate {}: {}", user, err));
+            attempt.reason = "init_failed".to_string();
+            return PamError::AUTHINFO_UNAVAIL;
+        }
+    };
+    let required = args.iter().any(|x| x == "verify_config");
+    if let Err(err) = crypto::verify_content(
+        base_path,
+        &config_file_path,
+        &content,
+        crypto::CONFIG_CONTEXT,
+        required,
+    ) {
+        audit::log(&format!("refusing to authenticate {}: {}", user, err));
+        attempt.reason = "unverified_config".to_string();
+        return PamError::AUTHINFO_UNAVAIL;
+    }
+
+    let config = match config::parse(&content) {
+        Ok(c) => c,
+        Err(err) => {
+            audit::log(&format!("refusing to authenticate {}: {}", user, err));
+            attempt.reason = "init_failed".to_string();
+            return PamError::AUTHINFO_UNAVAIL;
+        }
+    };
+
+    // In concurrent mode the scan runs while the password is being prompted for, otherwise
+    // terminal users can skip it with Enter or Ctrl+C
+    let typed = RefCell::new(None);
+    let mut scan_face = || match config.core.concurrent_password {
+        true => {
+            let (result, password) = scan_while_prompting(pamh, base_path, user, &config);
+            *typed.borrow_mut() = password;
+            result
+        }
+        false => {
+            let keys = KeyWatcher::open();
+            let mut stop = || keys.as_ref().map_or(false, |k| k.pressed());
+            scan(base_path, user, &config, &mut stop)
+        }
+    };
+    let mut messages = Conversation {
+        pamh,
+        typed: &typed,
+    };
+    let outcome = auth::authenticate(
+        base_path,
+        user,
+        &config,
+        attempt,
+        &mut messages,
+        &mut scan_face,
+    );
+
+    if outcome == Outcome::Identified {
+        pamh.send_bytes(FACE_LOGIN, vec![1], None).ok();
+        return PamError::SUCCESS;
+    }
+
+    // A password typed in concurrent mode is left to the next module
+    if let Some(p) = typed.into_inner() {
+        if attempt.reason == "aborted" {
+            attempt.reason = "password_entered".to_string();
+        }
+        return hand_over_password(pamh, p);
+    }
+
+    match outcome {
+        Outcome::Identified => PamError::SUCCESS,
+        Outcome::Failed => PamError::AUTH_ERR,
+        Outcome::Unavailable => PamError::AUTHINFO_UNAVAIL,
+        Outcome::UnknownUser => PamError::USER_UNKNOWN,
+    }
+}
+
+// Shows messages through the PAM conversation, except after a password was typed, which
+// is handed on to the next module instead
+struct Conversation<'a> {
+    pamh: &'a Pam,
+    typed: &'a RefCell<Option<CString>>,
+}
+
+impl Conversation<'_> {
+    fn show(&self, message: &str, style: PamMsgStyle) {
+        if self.typed.borrow().is_none() {
+            self.pamh.conv(Some(message), style).ok();
+        }
+    }
+}
+
+impl Messages for Conversation<'_> {
+    fn info(&mut self, message: &str) {
+        self.show(message, PamMsgStyle::TEXT_INFO);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.show(message, PamMsgStyle::ERROR_MSG);
+    }
+}
+
+// Scans for the user's face through holad when it's running, which keeps the models
+// loaded, otherwise loads everything in-process
+fn scan(
+    base_path: &Path,
+    user: &str,
+    config: &Config,
+    stop: &mut dyn FnMut() -> bool,
+) -> Result<ScanResult, String> {
+    let timeout = Duration::from_secs(config.video.timeout);
+    if let Some(result) = daemon::authenticate(user, timeout, stop) {
+        return Ok(result);
+    }
+    let mut app = App::with_config(base_path, user, config.clone()).map_err(|e| e.to_string())?;
+    Ok(app.scan(stop))
+}
+
+// Scans on another thread while prompting for the password on this one, as only the
+// application's thread may use the conversation. A conversation can't be interrupted, so
+// a face identified while the prompt is open counts once Enter is pressed. A typed
+// password stops the scan. The scan thread is always joined before returning.
+fn scan_while_prompting(
+    pamh: &Pam,
+    base_path: &Path,
+    user: &str,
+    config: &Config,
+) -> (Result<ScanResult, String>, Option<CString>) {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let scanner = {
+        let stopped = stopped.clone();
+        let base_path = base_path.to_path_buf();
+        let user = user.to_string();
+        let config = config.clone();
+        thread::spawn(move || {
+            let mut stop = || stopped.load(Ordering::SeqCst);
+            scan(&base_path, &user, &config, &mut stop)
+        })
+    };
+    let password = match pamh.conv(
+        Some("Password, or Enter to use your face: "),
+        PamMsgStyle::PROMPT_ECHO_OFF,
+    ) {
+        Ok(Some(p)) if !p.to_bytes().is_empty() => Some(p.to_owned()),
+        _ => None,
+    };
+    if password.is_some() {
+        stopped.store(true, Ordering::SeqCst);
+    }
+    let result = scanner
+        .join()
+        .unwrap_or_else(|_| Err("face scan failed".to_string()));
+    (result, password)
+}
+
+// Stores a typed password as the auth token so the next module, using try_first_pass or
+// use_first_pass, can verify it
+fn hand_over_password(pamh: &Pam, password: CString) -> PamError {
+    if let Err(e) = pamh.set_authtok(&password) {
+        return e;
+    }
+    PamError::AUTH_ERR
+}
+
+pam_module!(PamTime);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESTABLISH: i32 = PamFlag::PAM_ESTABLISH_CRED as i32;
+    const SILENT: i32 = PamFlag::PAM_SILENT as i32;
+
+    #[test]
+    fn records_passwords_when_establishing_credentials() {
+        assert!(password_used(ESTABLISH, false, true));
+        assert!(password_used(ESTABLISH | SILENT, false, true));
+    }
+
+    #[test]
+    fn ignores_face_logins_and_other_flags() {
+        assert!(!password_used(ESTABLISH, true, true));
+        // Hola didn't run or wasn't asked to authenticate in this handle
+        assert!(!password_used(ESTABLISH, false, false));
+        for flag in [
+            PamFlag::PAM_DELETE_CRED,
+            PamFlag::PAM_REINITIALIZE_CRED,
+            PamFlag::PAM_REFRESH_CRED,
+        ] {
+            let flag = flag as i32;
+            assert!(!password_used(flag, false, true));
+            assert!(!password_used(flag | SILENT, false, true));
+        }
+    }
+}
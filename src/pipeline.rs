@@ -0,0 +1,221 @@
+use crate::capture::Source;
+use crate::recognizer::{Detection, Recognizer};
+use crate::tracker::Tracker;
+use image::RgbImage;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How long next waits for a processed frame before giving the caller a chance to stop
+const RESULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Pause after a failed capture before trying again
+const CAPTURE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// How frames of the current run are processed
+#[derive(Clone, Copy, Default)]
+pub struct Settings {
+    pub max_height: u32,
+    pub use_cnn: bool,
+    // Only search around the face found in the previous frame
+    pub track: bool,
+}
+
+struct Shared {
+    // Freshest captured frame with the run it belongs to, older frames are dropped
+    // unprocessed when a newer one arrives before a worker is free
+    latest: Mutex<Option<(usize, RgbImage)>>,
+    ready: Condvar,
+    // Incremented on every start and stop, so results of a finished run are discarded
+    run: AtomicUsize,
+    capturing: AtomicBool,
+    closed: AtomicBool,
+    settings: Mutex<Settings>,
+    tracker: Mutex<Tracker>,
+}
+
+// Captures frames on one thread and runs detection and encoding on a pool of workers, each
+// with its own recognizer, so the camera keeps streaming while frames are processed
+pub struct Pipeline {
+    shared: Arc<Shared>,
+    results: Receiver<(usize, Detection)>,
+    workers: usize,
+    capture: Option<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    // Starts a worker for each recognizer
+    pub fn new(recognizers: Vec<Recognizer>) -> Self {
+        let shared = Arc::new(Shared {
+            latest: Mutex::new(None),
+            ready: Condvar::new(),
+            run: AtomicUsize::new(0),
+            capturing: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            settings: Mutex::new(Settings::default()),
+            tracker: Mutex::new(Tracker::new()),
+        });
+        let workers = recognizers.len();
+        let (sender, results) = sync_channel(workers);
+        for recognizer in recognizers {
+            let shared = shared.clone();
+            let sender = sender.clone();
+            thread::spawn(move || work(&shared, &recognizer, &sender));
+        }
+        Self {
+            shared,
+            results,
+            workers,
+            capture: None,
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    // Starts capturing from the source, stopping any previous capture
+    pub fn start(&mut self, mut source: Box<dyn Source>, settings: Settings) {
+        self.stop();
+        *self.shared.settings.lock().unwrap() = settings;
+        self.shared.tracker.lock().unwrap().reset();
+        let run = self.shared.run.fetch_add(1, Ordering::SeqCst) + 1;
+        self.shared.capturing.store(true, Ordering::SeqCst);
+        let shared = self.shared.clone();
+        self.capture = Some(thread::spawn(move || {
+            while shared.capturing.load(Ordering::SeqCst) {
+                match source.next_frame() {
+                    Ok(frame) => {
+                        *shared.latest.lock().unwrap() = Some((run, frame));
+                        shared.ready.notify_one();
+                    }
+                    Err(_) => thread::sleep(CAPTURE_RETRY_DELAY),
+                }
+            }
+        }));
+    }
+
+    // Stops capturing, releasing the source, and drops frames that weren't processed yet
+    pub fn stop(&mut self) {
+        self.shared.capturing.store(false, Ordering::SeqCst);
+        if let Some(capture) = self.capture.take() {
+            capture.join().ok();
+        }
+        self.shared.run.fetch_add(1, Ordering::SeqCst);
+        *self.shared.latest.lock().unwrap() = None;
+    }
+
+    // Waits for the next processed frame of the current run, None when none arrived in time
+    pub fn next(&self) -> Option<Detection> {
+        let deadline = Instant::now() + RESULT_TIMEOUT;
+        loop {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            match self.results.recv_timeout(timeout) {
+                Ok((run, detection)) if run == self.shared.run.load(Ordering::SeqCst) => {
+                    return Some(detection)
+                }
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    // Workers exit once they notice, taking their recognizers with them
+    fn drop(&mut self) {
+        self.stop();
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.ready.notify_all();
+    }
+}
+
+// Processes the freshest frame whenever there is one, until the pipeline is dropped
+fn work(shared: &Shared, recognizer: &Recognizer, sender: &SyncSender<(usize, Detection)>) {
+    loop {
+        let (run, image) = {
+            let mut latest = shared.latest.lock().unwrap();
+            loop {
+                if shared.closed.load(Ordering::SeqCst) {
+                    return;
+                }
+                if let Some(frame) = latest.take() {
+                    break frame;
+                }
+                latest = shared.ready.wait(latest).unwrap();
+            }
+        };
+        let settings = *shared.settings.lock().unwrap();
+        let region = match settings.track {
+            true => {
+                let width = image.width() * settings.max_height / image.height();
+                shared
+                    .tracker
+                    .lock()
+                    .unwrap()
+                    .region(width, settings.max_height)
+            }
+            false => None,
+        };
+        let detection = recognizer.process(&image, settings.max_height, settings.use_cnn, region);
+        if settings.track {
+            shared.tracker.lock().unwrap().update(&detection.faces);
+        }
+        if sender.send((run, detection)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{face_frame, MockSource};
+
+    fn start(pipeline: &mut Pipeline, frames: Vec<RgbImage>) {
+        let settings = Settings {
+            max_height: 48,
+            use_cnn: false,
+            track: true,
+        };
+        pipeline.start(Box::new(MockSource::new(frames)), settings);
+    }
+
+    #[test]
+    fn delivers_frames_of_the_current_run() {
+        let mut pipeline = Pipeline::new(vec![Recognizer::mock(), Recognizer::mock()]);
+        assert_eq!(pipeline.workers(), 2);
+        assert!(pipeline.next().is_none());
+
+        // Frames without faces are delivered too, as they count towards the attempt
+        start(&mut pipeline, vec![RgbImage::new(64, 48)]);
+        for _ in 0..3 {
+            let detection = pipeline.next().expect("no frame processed");
+            assert!(detection.faces.is_empty());
+        }
+
+        // Frames still being processed when the run stopped are dropped
+        pipeline.stop();
+        assert!(pipeline.next().is_none());
+        start(&mut pipeline, vec![face_frame([0, 200, 0])]);
+        let detection = pipeline.next().expect("no frame processed after restart");
+        assert_eq!(detection.faces.len(), 1);
+        assert!(detection.image.get_pixel(32, 24)[1] > 100);
+    }
+
+    #[test]
+    fn delivers_nothing_without_frames() {
+        let mut pipeline = Pipeline::new(vec![Recognizer::mock()]);
+        start(&mut pipeline, Vec::new());
+        assert!(pipeline.next().is_none());
+        assert!(pipeline.next().is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::backend::{Detector, Encoder, LandmarkModel, Rect};
+use crate::config::Config;
+#[cfg(feature = "dlib")]
+use crate::dlib;
+#[cfg(any(test, feature = "mock"))]
+use crate::mock;
+#[cfg(any(feature = "onnx", feature = "tract"))]
+use crate::onnx;
+use image::{imageops, RgbImage};
+use std::{
+    error::Error,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// Backends Hola was built with. ONNX models run on ONNX Runtime when it's built in and on
+// tract otherwise.
+pub fn backends() -> Vec<&'static str> {
+    let mut backends = Vec::new();
+    if cfg!(feature = "dlib") {
+        backends.push("dlib");
+    }
+    if cfg!(feature = "onnx") {
+        backends.push("onnx (ONNX Runtime)");
+    } else if cfg!(feature = "tract") {
+        backends.push("onnx (tract)");
+    }
+    backends
+}
+
+fn unsupported(backend: &str) -> Box<dyn Error> {
+    format!("Unknown backend {}, or Hola was built without it", backend).into()
+}
+
+#[cfg(feature = "dlib")]
+fn cnn_detector<P: AsRef<Path>>(base_path: P) -> Result<Box<dyn Detector>, Box<dyn Error>> {
+    Ok(Box::new(dlib::cnn_detector(base_path)?))
+}
+
+#[cfg(not(feature = "dlib"))]
+fn cnn_detector<P: AsRef<Path>>(_base_path: P) -> Result<Box<dyn Detector>, Box<dyn Error>> {
+    Err(unsupported("dlib"))
+}
+
+// Name and dimension of the encoder of the configured backend, without loading its models
+pub fn encoder<P: AsRef<Path>>(
+    base_path: P,
+    config: &Config,
+) -> Result<(String, usize), Box<dyn Error>> {
+    match config.core.backend.as_str() {
+        #[cfg(feature = "dlib")]
+        "dlib" => Ok((dlib::encoder_hash(base_path)?, dlib::ENCODING_DIMENSION)),
+        #[cfg(any(feature = "onnx", feature = "tract"))]
+        "onnx" => Ok((
+            onnx::encoder_hash(base_path, &config.onnx)?,
+            onnx::ENCODING_DIMENSION,
+        )),
+        backend => Err(unsupported(backend)),
+    }
+}
+
+// Faces found in a frame after resizing it
+pub struct Detection {
+    // The resized frame the boxes refer to
+    pub image: RgbImage,
+    pub faces: Vec<Rect>,
+    pub encodings: Vec<Vec<f64>>,
+}
+
+// Time spent in each stage of processing a frame
+#[derive(Clone, Copy, Default)]
+pub struct Stages {
+    pub resize: Duration,
+    pub detection: Duration,
+    pub landmarks: Duration,
+    pub encoding: Duration,
+}
+
+// Face detection and encoding through a detector, landmark model and encoder backend
+pub struct Recognizer {
+    detector: Box<dyn Detector>,
+    // Only loaded when the CNN detector is used
+    cnn_detector: Option<Box<dyn Detector>>,
+    landmarks: Box<dyn LandmarkModel>,
+    encoder: Box<dyn Encoder>,
+}
+
+impl Recognizer {
+    pub fn new(
+        detector: Box<dyn Detector>,
+        landmarks: Box<dyn LandmarkModel>,
+        encoder: Box<dyn Encoder>,
+    ) -> Self {
+        Self {
+            detector,
+            cnn_detector: None,
+            landmarks,
+            encoder,
+        }
+    }
+
+    // Loads the models of the configured backend
+    pub fn load<P: AsRef<Path>>(base_path: P, config: &Config) -> Result<Self, Box<dyn Error>> {
+        match config.core.backend.as_str() {
+            #[cfg(feature = "dlib")]
+            "dlib" => dlib::load(base_path, config.core.use_cnn),
+            #[cfg(any(feature = "onnx", feature = "tract"))]
+            "onnx" => onnx::load(base_path, &config.onnx),
+            backend => Err(unsupported(backend)),
+        }
+    }
+
+    // Deterministic recognizer for running without model files, see the mock module
+    #[cfg(any(test, feature = "mock"))]
+    pub fn mock() -> Self {
+        let mut recognizer = Self::new(
+            Box::new(mock::MockDetector),
+            Box::new(mock::MockLandmarks),
+            Box::new(mock::MockEncoder),
+        );
+        recognizer.cnn_detector = Some(Box::new(mock::MockDetector));
+        recognizer
+    }
+
+    // Loads the dlib CNN detector, if no CNN detector is loaded yet
+    pub fn load_cnn<P: AsRef<Path>>(&mut self, base_path: P) -> Result<(), Box<dyn Error>> {
+        if self.cnn_detector.is_none() {
+            self.cnn_detector = Some(cnn_detector(base_path)?);
+        }
+        Ok(())
+    }
+
+    // Name of the encoder, stored with the models it produced
+    pub fn encoder_name(&self) -> &str {
+        self.encoder.name()
+    }
+
+    pub fn encoder_dimension(&self) -> usize {
+        self.encoder.dimension()
+    }
+
+    // Resizes the frame to max_height, then finds and encodes all faces in it. With a region
+    // only that part of the frame is searched, unless no face is found there. Falls back to
+    // the HOG detector when the CNN detector wasn't loaded.
+    pub fn process(
+        &self,
+        image: &RgbImage,
+        max_height: u32,
+        use_cnn: bool,
+        region: Option<Rect>,
+    ) -> Detection {
+        self.process_timed(image, max_height, use_cnn, region).0
+    }
+
+    // Same as process, also returning how long each stage took. A region without a face
+    // counts towards the stages of the full frame search that follows.
+    pub fn process_timed(
+        &self,
+        image: &RgbImage,
+        max_height: u32,
+        use_cnn: bool,
+        region: Option<Rect>,
+    ) -> (Detection, Stages) {
+        let mut stages = Stages::default();
+        let start = Instant::now();
+        let width = image.width() * max_height / image.height();
+        let image = imageops::resize(image, width, max_height, imageops::FilterType::Triangle);
+        stages.resize = start.elapsed();
+        if let Some((left, top, right, bottom)) = region.filter(|r| r.2 > r.0 && r.3 > r.1) {
+            let crop = imageops::crop_imm(
+                &image,
+                left as u32,
+                top as u32,
+                (right - left + 1) as u32,
+                (bottom - top + 1) as u32,
+            )
+            .to_image();
+            let (faces, encodings) = self.encode(&crop, use_cnn, &mut stages);
+            if !faces.is_empty() {
+                let faces = faces
+                    .iter()
+                    .map(|&(l, t, r, b)| (l + left, t + top, r + left, b + top))
+                    .collect();
+                let detection = Detection {
+                    image,
+                    faces,
+                    encodings,
+                };
+                return (detection, stages);
+            }
+        }
+        let (faces, encodings) = self.encode(&image, use_cnn, &mut stages);
+        let detection = Detection {
+            image,
+            faces,
+            encodings,
+        };
+        (detection, stages)
+    }
+
+    fn encode(
+        &self,
+        image: &RgbImage,
+        use_cnn: bool,
+        stages: &mut Stages,
+    ) -> (Vec<Rect>, Vec<Vec<f64>>) {
+        let start = Instant::now();
+        let faces = match (&self.cnn_detector, use_cnn) {
+            (Some(cnn_detector), true) => cnn_detector.detect(image),
+            _ => self.detector.detect(image),
+        };
+        let detected = Instant::now();
+        let landmarks = self.landmarks.landmarks(image, &faces);
+        let located = Instant::now();
+        let encodings = self.encoder.encode(image, &landmarks);
+        stages.detection += detected - start;
+        stages.landmarks += located - detected;
+        stages.encoding += located.elapsed();
+        (faces, encodings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    // A small face on the left and a large one on the right
+    fn two_faces() -> RgbImage {
+        RgbImage::from_fn(64, 48, |x, y| {
+            let left = (4..12).contains(&x) && (8..20).contains(&y);
+            let right = (30..60).contains(&x) && (10..40).contains(&y);
+            match left || right {
+                true => Rgb([200, 200, 200]),
+                false => Rgb([0, 0, 0]),
+            }
+        })
+    }
+
+    #[test]
+    fn searches_only_the_region() {
+        let recognizer = Recognizer::mock();
+        let detection = recognizer.process(&two_faces(), 48, false, Some((24, 4, 63, 45)));
+        assert_eq!(detection.faces.len(), 1);
+        assert_eq!(detection.encodings.len(), 1);
+        // Boxes are mapped back onto the whole frame
+        let (left, top, right, bottom) = detection.faces[0];
+        assert!((29..=31).contains(&left) && (9..=11).contains(&top));
+        assert!((58..=60).contains(&right) && (38..=40).contains(&bottom));
+    }
+
+    #[test]
+    fn falls_back_to_the_whole_frame() {
+        let recognizer = Recognizer::mock();
+        // Nothing in the region, e.g. the face moved away
+        let detection = recognizer.process(&two_faces(), 48, false, Some((14, 0, 26, 47)));
+        assert_eq!(detection.faces.len(), 2);
+        // An empty region is ignored
+        let detection = recognizer.process(&two_faces(), 48, false, Some((20, 20, 20, 30)));
+        assert_eq!(detection.faces.len(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    ffi::CString,
+    fs::{read_dir, set_permissions, symlink_metadata, Permissions},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+// A file or directory that could be modified by someone other than root
+pub struct Issue {
+    pub path: PathBuf,
+    pub uid: u32,
+    pub mode: u32,
+    // Why the path couldn't be checked, such paths have to be fixed by hand
+    pub error: Option<String>,
+}
+
+impl Issue {
+    pub fn problem(&self) -> String {
+        if let Some(error) = self.error.as_ref() {
+            return error.clone();
+        }
+        let mut problems = Vec::new();
+        if self.uid != 0 {
+            problems.push(format!("owned by uid {}", self.uid));
+        }
+        if self.mode & 0o020 != 0 {
+            problems.push("group writable".to_string());
+        }
+        if self.mode & 0o002 != 0 {
+            problems.push("world writable".to_string());
+        }
+        problems.join(", ")
+    }
+
+    // Hands the path to root and drops group and world write permissions
+    pub fn fix(&self) -> io::Result<()> {
+        if let Some(error) = self.error.as_ref() {
+            return Err(io::Error::other(error.clone()));
+        }
+        if self.uid != 0 {
+            let path = CString::new(self.path.as_os_str().as_bytes())?;
+            if unsafe { libc::chown(path.as_ptr(), 0, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        set_permissions(&self.path, Permissions::from_mode(self.mode & 0o7755))
+    }
+}
+
+// Paths whose contents decide who can authenticate or hold biometric data, in the order
+// they are checked, with the directories that couldn't be listed
+fn checked_paths(base_path: &Path) -> (Vec<PathBuf>, Vec<Issue>) {
+    let mut paths = vec![base_path.to_path_buf(), base_path.join("config.toml")];
+    let mut unlisted = Vec::new();
+    let dirs = [
+        "dlib_models",
+        "onnx_models",
+        "models",
+        "keys",
+        "state",
+        "logs",
+        "snapshots",
+    ];
+    for dir in dirs.iter() {
+        let dir = base_path.join(dir);
+        if symlink_metadata(&dir).is_err() {
+            continue;
+        }
+        paths.push(dir.clone());
+        match read_dir(&dir) {
+            Ok(entries) => {
+                let mut files: Vec<PathBuf> =
+                    entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+                files.sort();
+                paths.extend(files);
+            }
+            Err(err) => unlisted.push(unchecked(dir, format!("not listable ({})", err))),
+        }
+    }
+    (paths, unlisted)
+}
+
+fn unchecked(path: PathBuf, error: String) -> Issue {
+    Issue {
+        path,
+        uid: 0,
+        mode: 0,
+        error: Some(error),
+    }
+}
+
+// Finds every path under the base path that isn't root owned or is group/world writable.
+// Symbolic links and paths that can't be checked are refused too.
+pub fn check_permissions<P: AsRef<Path>>(base_path: P) -> Vec<Issue> {
+    let (paths, unlisted) = checked_paths(base_path.as_ref());
+    let mut issues: Vec<Issue> = paths
+        .into_iter()
+        .filter_map(|path| {
+            let meta = match symlink_metadata(&path) {
+                Ok(meta) => meta,
+                Err(err) => return Some(unchecked(path, format!("unreadable ({})", err))),
+            };
+            let mode = meta.mode() & 0o7777;
+            let error = match meta.file_type().is_symlink() {
+                true => Some("a symbolic link".to_string()),
+                false if meta.uid() == 0 && mode & 0o022 == 0 => return None,
+                false => None,
+            };
+            Some(Issue {
+                path,
+                uid: meta.uid(),
+                mode,
+                error,
+            })
+        })
+        .collect();
+    issues.extend(unlisted);
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::test_base;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    fn problem(issues: &[Issue], name: &str) -> Option<String> {
+        issues
+            .iter()
+            .find(|x| x.path.ends_with(name))
+            .map(|x| x.problem())
+    }
+
+    #[test]
+    fn refuses_writable_files_and_links() {
+        let base_path = test_base("security");
+        create_dir_all(base_path.join("models")).unwrap();
+        create_dir_all(base_path.join("state")).unwrap();
+        let config = base_path.join("config.toml");
+        set_permissions(&config, Permissions::from_mode(0o646)).unwrap();
+        let models = base_path.join("models").join("alice.dat");
+        write(&models, b"[]").unwrap();
+        set_permissions(&models, Permissions::from_mode(0o664)).unwrap();
+        symlink("/etc/passwd", base_path.join("state").join("alice.json")).unwrap();
+
+        let issues = check_permissions(&base_path);
+        let link = issues.iter().find(|x| x.path.ends_with("alice.json"));
+        let fixed_link = link.map(|x| x.fix().is_err());
+        remove_dir_all(&base_path).ok();
+        assert!(problem(&issues, "config.toml")
+            .unwrap()
+            .ends_with("world writable"));
+        assert!(problem(&issues, "alice.dat")
+            .unwrap()
+            .ends_with("group writable"));
+        assert_eq!(
+            problem(&issues, "alice.json").as_deref(),
+            Some("a symbolic link")
+        );
+        // Links are never followed when fixing
+        assert_eq!(fixed_link, Some(true));
+    }
+
+    #[test]
+    fn fixes_modes() {
+        let base_path = test_base("security-fix");
+        let config = base_path.join("config.toml");
+        set_permissions(&config, Permissions::from_mode(0o666)).unwrap();
+        let issues = check_permissions(&base_path);
+        let fixed = issues.iter().all(|x| x.fix().is_ok());
+        let mode = symlink_metadata(&config).unwrap().mode() & 0o777;
+        let root = unsafe { libc::geteuid() } == 0;
+        let left = check_permissions(&base_path).len();
+        remove_dir_all(&base_path).ok();
+        // Only root can hand the files to root
+        if root {
+            assert_eq!(mode, 0o644);
+            assert!(fixed);
+            assert_eq!(left, 0);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::app::Frame;
+use crate::crypto;
+use crate::helper::write_atomic;
+use chrono::prelude::Local;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{read, read_dir, read_to_string, remove_file, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+};
+
+// Details stored next to each snapshot image
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub time: i64,
+    pub user: String,
+    pub success: bool,
+    pub distance: Option<f64>,
+    pub faces: usize,
+    // Encrypted with the machine key of the face models
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl Snapshot {
+    pub fn image_path<P: AsRef<Path>>(&self, base_path: P) -> PathBuf {
+        let extension = match self.encrypted {
+            true => "png.enc",
+            false => "png",
+        };
+        snapshot_dir(base_path).join(format!("{}.{}", self.name, extension))
+    }
+
+    // Encryption context, so images can't be swapped between snapshots
+    fn context(&self) -> String {
+        format!("snapshot:{}", self.name)
+    }
+
+    // The PNG image, decrypted when needed
+    pub fn read_image<P: AsRef<Path>>(&self, base_path: P) -> Result<Vec<u8>, Box<dyn Error>> {
+        let content = read(self.image_path(&base_path))?;
+        if !self.encrypted {
+            return Ok(content);
+        }
+        let key = crypto::load_key(&base_path, crypto::MODEL_KEY, false)?;
+        crypto::decrypt(&key, &content, &self.context())
+    }
+}
+
+pub fn snapshot_dir<P: AsRef<Path>>(base_path: P) -> PathBuf {
+    base_path.as_ref().join("snapshots")
+}
+
+// 3x5 pixel glyphs of the characters a distance is written with, one row per byte with
+// the leftmost pixel in the highest of the three bits
+const GLYPHS: [(char, [u8; 5]); 11] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+];
+
+// Size of a glyph pixel in image pixels
+const TEXT_SCALE: i64 = 2;
+
+// Draws text with its top left corner at x and y, clipped to the image. Characters
+// without a glyph are left blank.
+fn draw_text(image: &mut RgbImage, text: &str, x: i64, y: i64, color: Rgb<u8>) {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    for (i, c) in text.chars().enumerate() {
+        let rows = match GLYPHS.iter().find(|(g, _)| *g == c) {
+            Some((_, rows)) => rows,
+            None => continue,
+        };
+        let left = x + i as i64 * 4 * TEXT_SCALE;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..TEXT_SCALE {
+                    for dx in 0..TEXT_SCALE {
+                        let px = left + column * TEXT_SCALE + dx;
+                        let py = y + row as i64 * TEXT_SCALE + dy;
+                        if px >= 0 && py >= 0 && px < width && py < height {
+                            image.put_pixel(px as u32, py as u32, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Draws a rectangle outline, clipped to the image
+fn draw_box(image: &mut RgbImage, face: (i64, i64, i64, i64), color: Rgb<u8>) {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let (left, top, right, bottom) = face;
+    let left = left.max(0).min(width - 1);
+    let right = right.max(0).min(width - 1);
+    let top = top.max(0).min(height - 1);
+    let bottom = bottom.max(0).min(height - 1);
+    for x in left..=right {
+        image.put_pixel(x as u32, top as u32, color);
+        image.put_pixel(x as u32, bottom as u32, color);
+    }
+    for y in top..=bottom {
+        image.put_pixel(left as u32, y as u32, color);
+        image.put_pixel(right as u32, y as u32, color);
+    }
+}
+
+// Saves a frame with the faces marked, green for a success and red otherwise, and the
+// distance written above the largest face, then removes the oldest snapshots beyond the
+// retention limit. Snapshots are encrypted like the face models when asked to.
+pub fn save<P: AsRef<Path>>(
+    base_path: P,
+    user: &str,
+    frame: &Frame,
+    success: bool,
+    distance: Option<f64>,
+    max_count: usize,
+    encrypt: bool,
+) -> Result<(), Box<dyn Error>> {
+    let dir = snapshot_dir(&base_path);
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    let now = Local::now();
+    let snapshot = Snapshot {
+        name: format!(
+            "{}_{}_{}",
+            now.format("%Y%m%d-%H%M%S%.3f"),
+            user,
+            match success {
+                true => "success",
+                false => "failed",
+            }
+        ),
+        time: now.timestamp(),
+        user: user.to_string(),
+        success,
+        distance,
+        faces: frame.faces.len(),
+        encrypted: encrypt,
+    };
+    let color = match success {
+        true => Rgb([0, 255, 0]),
+        false => Rgb([255, 0, 0]),
+    };
+    let mut image = frame.image.clone();
+    for face in frame.faces.iter() {
+        draw_box(&mut image, *face, color);
+    }
+    if let Some(distance) = distance {
+        // Below the face when there's no room above it, top left without a face
+        let text_height = 5 * TEXT_SCALE;
+        let (x, y) = match frame
+            .faces
+            .iter()
+            .max_by_key(|(left, top, right, bottom)| (right - left) * (bottom - top))
+        {
+            Some(&(left, top, _, _)) if top > text_height + 2 => (left, top - text_height - 2),
+            Some(&(left, _, _, bottom)) => (left, bottom + 3),
+            None => (2, 2),
+        };
+        draw_text(&mut image, &format!("{:.4}", distance), x, y, color);
+    }
+    let mut content = Vec::new();
+    DynamicImage::ImageRgb8(image).write_to(&mut content, ImageOutputFormat::Png)?;
+    if encrypt {
+        let key = crypto::load_key(&base_path, crypto::MODEL_KEY, true)?;
+        content = crypto::encrypt(&key, &content, &snapshot.context())?;
+    }
+    write_atomic(snapshot.image_path(&base_path), &content)?;
+    let info_path = dir.join(format!("{}.json", snapshot.name));
+    write_atomic(&info_path, &serde_json::to_vec(&snapshot)?)?;
+
+    let snapshots = list(&base_path)?;
+    if snapshots.len() > max_count {
+        for s in snapshots[..snapshots.len() - max_count].iter() {
+            remove(&base_path, s)?;
+        }
+    }
+    Ok(())
+}
+
+// Path of a PNG image viewers can open, for encrypted snapshots a decrypted copy in a
+// private temporary directory
+pub fn viewable_path<P: AsRef<Path>>(
+    base_path: P,
+    snapshot: &Snapshot,
+) -> Result<PathBuf, Box<dyn Error>> {
+    if !snapshot.encrypted {
+        return Ok(snapshot.image_path(base_path));
+    }
+    let dir = std::env::temp_dir().join(format!("hola-snapshots-{}", std::process::id()));
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    let path = dir.join(format!("{}.png", snapshot.name));
+    write_atomic(&path, &snapshot.read_image(base_path)?)?;
+    Ok(path)
+}
+
+// Lists all snapshots, oldest first
+pub fn list<P: AsRef<Path>>(base_path: P) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+    let dir = snapshot_dir(&base_path);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut snapshots: Vec<Snapshot> = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |x| x != "json") {
+            continue;
+        }
+        if let Ok(s) = serde_json::from_str(&read_to_string(&path)?) {
+            snapshots.push(s);
+        }
+    }
+    snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(snapshots)
+}
+
+pub fn remove<P: AsRef<Path>>(base_path: P, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+    remove_file(snapshot.image_path(&base_path)).ok();
+    remove_file(snapshot_dir(&base_path).join(format!("{}.json", snapshot.name)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::test_base;
+    use std::{fs::remove_dir_all, os::unix::fs::MetadataExt};
+
+    fn frame() -> Frame {
+        Frame {
+            image: RgbImage::from_pixel(32, 24, Rgb([40, 40, 40])),
+            faces: vec![(8, 8, 20, 20)],
+        }
+    }
+
+    // Mode of the image and info files of a snapshot
+    fn modes(base_path: &Path, snapshot: &Snapshot) -> (u32, u32) {
+        let info_path = snapshot_dir(base_path).join(format!("{}.json", snapshot.name));
+        let mode = |path: PathBuf| path.metadata().unwrap().mode() & 0o777;
+        (mode(snapshot.image_path(base_path)), mode(info_path))
+    }
+
+    #[test]
+    fn saves_private_snapshots() {
+        let base_path = test_base("snapshot-save");
+        save(&base_path, "alice", &frame(), false, Some(0.71), 2, false).unwrap();
+        let snapshots = list(&base_path).unwrap();
+        let image = image::load_from_memory(&snapshots[0].read_image(&base_path).unwrap());
+        let modes = modes(&base_path, &snapshots[0]);
+
+        // Only the newest are kept, names differ by the millisecond
+        for _ in 0..2 {
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            save(&base_path, "alice", &frame(), true, None, 2, false).unwrap();
+        }
+        let kept = list(&base_path).unwrap();
+        remove_dir_all(&base_path).ok();
+        assert_eq!(snapshots.len(), 1);
+        assert!(!snapshots[0].success);
+        assert_eq!(image.unwrap().to_rgb8().dimensions(), (32, 24));
+        assert_eq!(modes, (0o600, 0o600));
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|x| x.success));
+    }
+
+    #[test]
+    fn encrypts_snapshots() {
+        let base_path = test_base("snapshot-encrypt");
+        save(&base_path, "alice", &frame(), true, Some(0.3), 5, true).unwrap();
+        let snapshot = list(&base_path).unwrap().remove(0);
+        let stored = read(snapshot.image_path(&base_path)).unwrap();
+        let image = image::load_from_memory(&snapshot.read_image(&base_path).unwrap());
+        let modes = modes(&base_path, &snapshot);
+        remove_dir_all(&base_path).ok();
+        assert!(snapshot.encrypted);
+        assert!(crypto::is_encrypted(&stored));
+        assert_eq!(image.unwrap().to_rgb8().dimensions(), (32, 24));
+        assert_eq!(modes, (0o600, 0o600));
+    }
+}
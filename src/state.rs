@@ -0,0 +1,159 @@
+use crate::helper::{lock_file, write_atomic};
+use chrono::prelude::Local;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{create_dir_all, read_to_string, File},
+    path::{Path, PathBuf},
+};
+
+// Per-user authentication state persisted across PAM calls
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct State {
+    // Timestamps of failed attempts inside the lockout window
+    #[serde(default)]
+    pub failures: Vec<i64>,
+    #[serde(default)]
+    pub locked_until: i64,
+    // Face logins since the last password authentication
+    #[serde(default)]
+    pub face_logins: u32,
+    #[serde(default)]
+    pub last_password: i64,
+}
+
+// Locked handle to a user's state file, the lock is released on drop
+pub struct StateFile {
+    path: PathBuf,
+    pub state: State,
+    _lock: File,
+}
+
+impl StateFile {
+    pub fn open<P: AsRef<Path>>(base_path: P, user: &str) -> Result<Self, Box<dyn Error>> {
+        let dir = base_path.as_ref().join("state");
+        create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", user));
+        let lock = lock_file(&path)?;
+        let state = match read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(_) => State::default(),
+        };
+        Ok(Self {
+            path,
+            state,
+            _lock: lock,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        write_atomic(&self.path, &serde_json::to_vec(&self.state)?)?;
+        Ok(())
+    }
+}
+
+impl State {
+    // Seconds left until the lockout expires
+    pub fn locked_for(&self) -> Option<i64> {
+        let left = self.locked_until - Local::now().timestamp();
+        if left > 0 {
+            return Some(left);
+        }
+        None
+    }
+
+    // Records a failed attempt, locking once too many failures fall inside the window
+    pub fn record_failure(&mut self, max_failures: usize, window: i64, duration: i64) {
+        let now = Local::now().timestamp();
+        self.failures.retain(|x| now - x < window);
+        self.failures.push(now);
+        if max_failures > 0 && self.failures.len() >= max_failures {
+            self.locked_until = now + duration;
+            self.failures.clear();
+        }
+    }
+
+    // Whether a password is due, after too many face logins or too long since the last one.
+    // A limit of 0 disables the check. The interval starts with the first recorded password,
+    // before that it isn't known when the password was last entered.
+    pub fn password_required(&self, max_face_logins: u32, password_interval: u64) -> bool {
+        if max_face_logins > 0 && self.face_logins >= max_face_logins {
+            return true;
+        }
+        if password_interval > 0 && self.last_password > 0 {
+            let elapsed = Local::now().timestamp() - self.last_password;
+            return elapsed >= password_interval as i64 * 3600;
+        }
+        false
+    }
+
+    pub fn record_face_login(&mut self) {
+        self.face_logins += 1;
+    }
+
+    pub fn record_password(&mut self) {
+        self.face_logins = 0;
+        self.last_password = Local::now().timestamp();
+    }
+
+    pub fn reset_lockout(&mut self) {
+        self.failures.clear();
+        self.locked_until = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_after_failures_in_window() {
+        let now = Local::now().timestamp();
+        let mut state = State {
+            failures: vec![now - 1000, now - 10],
+            ..Default::default()
+        };
+        // The failure outside the window is forgotten
+        state.record_failure(3, 900, 600);
+        assert_eq!(state.failures.len(), 2);
+        assert_eq!(state.locked_for(), None);
+
+        state.record_failure(3, 900, 600);
+        assert!(state.failures.is_empty());
+        let left = state.locked_for().unwrap();
+        assert!(left > 590 && left <= 600);
+
+        state.reset_lockout();
+        assert_eq!(state.locked_for(), None);
+    }
+
+    #[test]
+    fn never_locks_without_limit() {
+        let mut state = State::default();
+        for _ in 0..10 {
+            state.record_failure(0, 900, 600);
+        }
+        assert_eq!(state.failures.len(), 10);
+        assert_eq!(state.locked_for(), None);
+    }
+
+    #[test]
+    fn requires_password_after_logins_or_interval() {
+        let mut state = State::default();
+        // Nothing is due before the first password was recorded
+        assert!(!state.password_required(2, 1));
+
+        state.record_face_login();
+        assert!(!state.password_required(2, 0));
+        state.record_face_login();
+        assert!(state.password_required(2, 0));
+        assert!(!state.password_required(0, 0));
+
+        state.record_password();
+        assert_eq!(state.face_logins, 0);
+        assert!(!state.password_required(2, 1));
+        state.last_password -= 3600;
+        assert!(state.password_required(0, 1));
+        assert!(!state.password_required(0, 0));
+    }
+}
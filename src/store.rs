@@ -0,0 +1,437 @@
+use crate::crypto;
+use crate::helper::{lock_file, write_atomic};
+use chrono::prelude::Local;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{copy, create_dir_all, read, remove_file, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+// Model stuct for user face encoding
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Model {
+    data: Vec<f64>,
+    pub label: String,
+    pub id: usize,
+    pub time: i64,
+    #[serde(default)]
+    pub auto: bool,
+}
+
+// Current version of the model file format
+pub const MODEL_FILE_VERSION: u32 = 1;
+
+// Versioned container stored in models/<user>.dat
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ModelFile {
+    pub version: u32,
+    // Name of the encoder the models were produced with, the SHA-256 of the dlib ResNet
+    // network or a backend prefix followed by the hash of its network. Empty until the
+    // encoder is known, e.g. after migrating version 0.
+    pub encoder: String,
+    pub dimension: usize,
+    pub created: i64,
+    pub updated: i64,
+    pub next_id: usize,
+    pub models: Vec<Model>,
+}
+
+impl ModelFile {
+    pub fn new(encoder: &str, dimension: usize) -> Self {
+        let now = Local::now().timestamp();
+        Self {
+            version: MODEL_FILE_VERSION,
+            encoder: encoder.to_string(),
+            dimension,
+            created: now,
+            updated: now,
+            next_id: 0,
+            models: Vec::new(),
+        }
+    }
+
+    // Parses a model file, upgrading older formats to the current version.
+    // Returns whether the content was migrated and should be written back.
+    pub fn parse(content: &str) -> Result<(Self, bool), Box<dyn Error>> {
+        let value: serde_json::Value = serde_json::from_str(content)?;
+        let mut migrated = false;
+        let mut file = if value.is_array() {
+            // Version 0 was a bare array of models, always produced by the dlib ResNet encoder.
+            // The encoder is adopted once it's known to produce encodings of the same size.
+            let models: Vec<Model> = serde_json::from_value(value)?;
+            let dimension = models.first().map_or(0, |x| x.data.len());
+            let mut file = Self::new("", dimension);
+            file.created = models.iter().map(|x| x.time).min().unwrap_or(file.created);
+            file.models = models;
+            migrated = true;
+            file
+        } else {
+            let file: Self = serde_json::from_value(value)?;
+            if file.version > MODEL_FILE_VERSION {
+                return Err(format!(
+                    "Model file version {} is newer than the supported version {}",
+                    file.version, MODEL_FILE_VERSION
+                )
+                .into());
+            }
+            file
+        };
+
+        // Older versions derived IDs from the model count, so removing and adding models
+        // could produce duplicates. Give every duplicate a fresh ID.
+        let mut seen = Vec::new();
+        let max_id = file.models.iter().map(|x| x.id + 1).max().unwrap_or(0);
+        if file.next_id < max_id {
+            file.next_id = max_id;
+            migrated = true;
+        }
+        for m in file.models.iter_mut() {
+            if seen.contains(&m.id) {
+                m.id = file.next_id;
+                file.next_id += 1;
+                migrated = true;
+            }
+            seen.push(m.id);
+        }
+        Ok((file, migrated))
+    }
+
+    // Whether the models can be compared against encodings from the given encoder
+    pub fn compatible(&self, encoder: &str, dimension: usize) -> bool {
+        (self.encoder.is_empty() || self.encoder == encoder)
+            && self.dimension == dimension
+            && self.models.iter().all(|x| x.data.len() == self.dimension)
+    }
+}
+
+// Euclidean distance between two face encodings
+pub fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f64>()
+        .sqrt()
+}
+
+// A user's face models as stored in models/<user>.dat, encrypted and signed as configured
+pub struct Store {
+    base_path: PathBuf,
+    user: String,
+    // Name and dimension of the encoder producing new encodings, once set_encoder was called
+    encoder: Option<(String, usize)>,
+    encrypt: bool,
+    file: ModelFile,
+    lock: Option<File>,
+}
+
+impl Store {
+    // Opens the user's models without knowing the encoder, which commands that only manage
+    // the stored models never need. Call set_encoder before comparing or adding encodings.
+    pub fn open<P: AsRef<Path>>(
+        base_path: P,
+        user: &str,
+        encrypt: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        create_dir_all(base_path.as_ref().join("models"))?;
+        let mut store = Self {
+            base_path: base_path.as_ref().to_path_buf(),
+            user: user.to_string(),
+            encoder: None,
+            encrypt,
+            file: ModelFile::new("", 0),
+            lock: None,
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    // Sets the encoder producing new encodings, which untagged models adopt when their
+    // size matches
+    pub fn set_encoder(&mut self, encoder: &str, dimension: usize) {
+        self.encoder = Some((encoder.to_string(), dimension));
+        self.adopt();
+    }
+
+    fn adopt(&mut self) {
+        if let Some((encoder, dimension)) = self.encoder.as_ref() {
+            if self.file.encoder.is_empty() && self.file.compatible(encoder, *dimension) {
+                self.file.encoder = encoder.clone();
+            }
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.base_path
+            .join("models")
+            .join(&format!("{}.dat", self.user))
+    }
+
+    // Previous version of the model file, kept by save so models lost to a bad change can
+    // be restored by copying it back by hand
+    pub fn backup_path(&self) -> PathBuf {
+        self.path().with_extension("bak")
+    }
+
+    // Reads the user's model file, writing it back when it was missing or migrated. The
+    // write happens under the lock, reading again once it's taken in case another process
+    // saved in between.
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let (file, migrated) = self.read()?;
+        self.file = file;
+        if migrated && self.lock.is_none() {
+            let _lock = lock_file(self.path())?;
+            let (file, migrated) = self.read()?;
+            self.file = file;
+            if migrated {
+                self.save()?;
+            }
+        } else if migrated {
+            self.save()?;
+        }
+        self.adopt();
+        Ok(())
+    }
+
+    // Parses the user's model file, a missing file is an empty one
+    fn read(&self) -> Result<(ModelFile, bool), Box<dyn Error>> {
+        let path = self.path();
+        let (file, migrated) = match read(&path) {
+            Ok(mut content) => {
+                let required = crypto::signing_enabled(&self.base_path);
+                crypto::verify_content(&self.base_path, &path, &content, &self.user, required)?;
+                if crypto::is_encrypted(&content) {
+                    let key = crypto::load_key(&self.base_path, crypto::MODEL_KEY, false)?;
+                    content = crypto::decrypt(&key, &content, &self.user)?;
+                }
+                let content = String::from_utf8(content)?;
+                ModelFile::parse(&content)
+                    .map_err(|e| format!("Failed to parse model file: {}", e))?
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => (ModelFile::new("", 0), true),
+            Err(err) => return Err(format!("Failed to read model file: {}", err).into()),
+        };
+        Ok((file, migrated))
+    }
+
+    // Takes an exclusive lock on the user's models and reloads them, so a read-modify-write
+    // can't interleave with another process. The lock is held until unlock or drop.
+    pub fn lock(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.lock.is_none() {
+            self.lock = Some(lock_file(self.path())?);
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    // Releases the lock taken by lock(), letting other processes modify the models
+    pub fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file.updated = Local::now().timestamp();
+        let mut content = serde_json::to_vec(&self.file)?;
+        if self.encrypt {
+            let key = crypto::load_key(&self.base_path, crypto::MODEL_KEY, true)?;
+            content = crypto::encrypt(&key, &content, &self.user)?;
+        }
+        // A plaintext backup must not outlive the switch to encryption
+        let path = self.path();
+        match read(&path) {
+            Ok(previous) if self.encrypt && !crypto::is_encrypted(&previous) => {
+                if self.backup_path().exists() {
+                    remove_file(self.backup_path())?;
+                }
+            }
+            Ok(_) => {
+                copy(&path, self.backup_path())?;
+            }
+            Err(_) => {}
+        }
+        write_atomic(&path, &content)?;
+        if crypto::signing_enabled(&self.base_path) {
+            crypto::sign_file(&self.base_path, self.path(), &self.user)?;
+        }
+        Ok(())
+    }
+
+    pub fn models(&self) -> &[Model] {
+        &self.file.models
+    }
+
+    // Whether the stored models were produced by the current encoder network. Without
+    // models any encoder is fine, the file adopts the encoder of the first one added. Before
+    // set_encoder the encoder is unknown and the models are assumed to be compatible.
+    pub fn compatible(&self) -> bool {
+        match self.encoder.as_ref() {
+            Some((encoder, dimension)) => {
+                self.file.models.is_empty() || self.file.compatible(encoder, *dimension)
+            }
+            None => true,
+        }
+    }
+
+    // IDs are persisted and never reused, unlike positions in the model list
+    fn next_id(&mut self) -> usize {
+        let id = self.file.next_id;
+        self.file.next_id += 1;
+        id
+    }
+
+    pub fn push(&mut self, data: Vec<f64>, label: String) -> usize {
+        if self.file.models.is_empty() {
+            self.clear();
+        }
+        let id = self.next_id();
+        self.file.models.push(Model {
+            data,
+            id,
+            label,
+            time: Local::now().timestamp(),
+            auto: false,
+        });
+        id
+    }
+
+    // Adds an auto-learned sample, dropping the oldest ones beyond the pool size
+    pub fn learn(&mut self, data: Vec<f64>, max_samples: usize) {
+        let id = self.next_id();
+        self.file.models.push(Model {
+            data,
+            id,
+            label: "auto".to_string(),
+            time: Local::now().timestamp(),
+            auto: true,
+        });
+        let mut auto_count = self.file.models.iter().filter(|x| x.auto).count();
+        while auto_count > max_samples {
+            let oldest = self
+                .file
+                .models
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.auto)
+                .min_by_key(|(_, x)| x.time)
+                .map(|(i, _)| i)
+                .unwrap();
+            self.file.models.remove(oldest);
+            auto_count -= 1;
+        }
+    }
+
+    pub fn purge_auto(&mut self) -> usize {
+        let count = self.file.models.len();
+        self.file.models.retain(|x| !x.auto);
+        count - self.file.models.len()
+    }
+
+    // Finds the IDs of models matching either an ID or a label
+    pub fn find(&self, key: &str) -> Vec<usize> {
+        if let Ok(id) = key.parse::<usize>() {
+            if self.file.models.iter().any(|x| x.id == id) {
+                return vec![id];
+            }
+        }
+        self.file
+            .models
+            .iter()
+            .filter(|x| x.label == key)
+            .map(|x| x.id)
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.file.models.retain(|x| x.id != id);
+    }
+
+    // Clearing also adopts the current encoder, so models from another network can be
+    // replaced. Without one set the file is left untagged until the first model is added.
+    pub fn clear(&mut self) {
+        self.file.models = Vec::new();
+        let (encoder, dimension) = self.encoder.clone().unwrap_or_default();
+        self.file.encoder = encoder;
+        self.file.dimension = dimension;
+    }
+
+    // The model closest to the encoding, with its distance
+    pub fn closest(&self, encoding: &[f64]) -> Option<(f64, &Model)> {
+        self.file
+            .models
+            .iter()
+            .map(|x| (distance(encoding, &x.data), x))
+            // A broken encoding must never look like a match
+            .filter(|(d, _)| d.is_finite())
+            .fold(None, |best: Option<(f64, &Model)>, (d, x)| match best {
+                Some((b, _)) if b <= d => best,
+                _ => Some((d, x)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renumbers_duplicate_ids() {
+        let content = r#"[
+            {"data": [0.1], "label": "a", "id": 0, "time": 1},
+            {"data": [0.2], "label": "b", "id": 1, "time": 2},
+            {"data": [0.3], "label": "c", "id": 1, "time": 3}
+        ]"#;
+        let (file, migrated) = ModelFile::parse(content).unwrap();
+        assert!(migrated);
+        let ids: Vec<usize> = file.models.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(file.next_id, 3);
+
+        // A current file with unique IDs is left alone
+        let content = serde_json::to_string(&file).unwrap();
+        let (parsed, migrated) = ModelFile::parse(&content).unwrap();
+        assert!(!migrated);
+        assert_eq!(parsed.next_id, 3);
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let content = r#"[
+            {"data": [0.1, 0.2], "label": "a", "id": 0, "time": 5},
+            {"data": [0.3, 0.4], "label": "b", "id": 1, "time": 3}
+        ]"#;
+        let (file, migrated) = ModelFile::parse(content).unwrap();
+        assert!(migrated);
+        assert_eq!(file.version, MODEL_FILE_VERSION);
+        assert_eq!(file.created, 3);
+        assert_eq!(file.next_id, 2);
+        // The encoder is unknown until the store is told, only the size has to match
+        assert_eq!(file.encoder, "");
+        assert!(file.compatible("dlib", 2));
+        assert!(!file.compatible("dlib", 128));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut file = ModelFile::new("mock", 128);
+        file.version = MODEL_FILE_VERSION + 1;
+        let content = serde_json::to_string(&file).unwrap();
+        assert!(ModelFile::parse(&content).is_err());
+    }
+
+    #[test]
+    fn never_matches_broken_encodings() {
+        let base_path = crate::helper::test_base("store-closest");
+        let mut store = Store::open(&base_path, "alice", false).unwrap();
+        store.set_encoder("mock", 2);
+        store.push(vec![f64::NAN, 0.0], "broken".to_string());
+        store.push(vec![1.0, 0.0], "face".to_string());
+        let closest = store
+            .closest(&[0.0, 0.0])
+            .map(|(d, m)| (d, m.label.clone()));
+        let broken = store.closest(&[f64::NAN, 0.0]).is_none();
+        std::fs::remove_dir_all(&base_path).ok();
+        assert_eq!(closest, Some((1.0, "face".to_string())));
+        assert!(broken);
+    }
+}
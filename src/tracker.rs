@@ -0,0 +1,90 @@
+use crate::backend::Rect;
+
+// How far the search region extends beyond the last face box, relative to its size
+const MARGIN: f64 = 0.5;
+
+// Every this many frames the whole frame is searched anyway, to notice other faces
+const FULL_DETECTION_INTERVAL: u32 = 5;
+
+// Follows the largest face across frames, so the detector only has to search the region
+// around where it was last seen
+#[derive(Default)]
+pub struct Tracker {
+    last: Option<Rect>,
+    frames: u32,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Forgets the face, so the next frame is searched in full
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.frames = 0;
+    }
+
+    // Region of a frame of the given size to search for the face, None for the whole frame
+    pub fn region(&mut self, width: u32, height: u32) -> Option<Rect> {
+        self.frames += 1;
+        if self.frames % FULL_DETECTION_INTERVAL == 0 {
+            return None;
+        }
+        let (left, top, right, bottom) = self.last?;
+        let dx = ((right - left) as f64 * MARGIN) as i64;
+        let dy = ((bottom - top) as f64 * MARGIN) as i64;
+        Some((
+            (left - dx).max(0),
+            (top - dy).max(0),
+            (right + dx).min(width as i64 - 1),
+            (bottom + dy).min(height as i64 - 1),
+        ))
+    }
+
+    // Records the faces found in the frame, losing track when there were none
+    pub fn update(&mut self, faces: &[Rect]) {
+        self.last = faces
+            .iter()
+            .max_by_key(|(left, top, right, bottom)| (right - left) * (bottom - top))
+            .copied();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_around_the_last_face() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.region(320, 240), None);
+
+        tracker.update(&[(10, 10, 30, 30), (100, 80, 160, 160)]);
+        // The largest face with half its size around it, clipped to the frame
+        assert_eq!(tracker.region(320, 240), Some((70, 40, 190, 200)));
+        tracker.update(&[(0, 200, 40, 239)]);
+        assert_eq!(tracker.region(320, 240), Some((0, 181, 60, 239)));
+    }
+
+    #[test]
+    fn falls_back_to_the_whole_frame() {
+        let mut tracker = Tracker::new();
+        tracker.update(&[(100, 80, 160, 160)]);
+        let regions: Vec<bool> = (0..FULL_DETECTION_INTERVAL * 2)
+            .map(|_| tracker.region(320, 240).is_some())
+            .collect();
+        // Every few frames, to notice other faces
+        assert_eq!(
+            regions,
+            vec![true, true, true, true, false, true, true, true, true, false]
+        );
+
+        // Losing the face or resetting searches the whole frame
+        tracker.update(&[]);
+        assert_eq!(tracker.region(320, 240), None);
+        tracker.update(&[(100, 80, 160, 160)]);
+        tracker.reset();
+        assert_eq!(tracker.region(320, 240), None);
+    }
+}
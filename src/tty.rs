@@ -0,0 +1,77 @@
+use std::{ffi::CStr, mem::MaybeUninit};
+
+const CTRL_C: libc::cc_t = 0x03;
+
+// Watches the controlling terminal for Enter or Ctrl+C while scanning. The terminal keeps
+// its line editing with echo off, and Ctrl+C ends a line instead of sending a signal.
+// It's restored when dropped.
+pub struct KeyWatcher {
+    fd: libc::c_int,
+    original: libc::termios,
+}
+
+impl KeyWatcher {
+    // Returns None without a controlling terminal, e.g. for graphical PAM clients
+    pub fn open() -> Option<Self> {
+        let path = CStr::from_bytes_with_nul(b"/dev/tty\0").unwrap();
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+            unsafe { libc::close(fd) };
+            return None;
+        }
+        let original = unsafe { termios.assume_init() };
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VEOL] = CTRL_C;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            unsafe { libc::close(fd) };
+            return None;
+        }
+        Some(Self { fd, original })
+    }
+
+    // Whether a line was ended with Enter or Ctrl+C. Input only becomes readable once a
+    // line is complete, so nothing is read before. A line ended with Ctrl+C is discarded,
+    // a longer line ended with Enter, like a password typed ahead, is put back for the
+    // prompt that follows.
+    pub fn pressed(&self) -> bool {
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.fd, libc::FIONREAD, &mut pending) } != 0 || pending <= 0 {
+            return false;
+        }
+        let mut line = vec![0u8; pending as usize];
+        let read =
+            unsafe { libc::read(self.fd, line.as_mut_ptr() as *mut libc::c_void, line.len()) };
+        line.truncate(read.max(0) as usize);
+        if line.len() > 1 && line.last() == Some(&b'\n') && self.put_back(&line) {
+            return true;
+        }
+        unsafe { libc::tcflush(self.fd, libc::TCIFLUSH) };
+        true
+    }
+
+    // Injects a line as input again, which needs root on current kernels. What was put back
+    // is flushed when that fails, the password then has to be typed again.
+    fn put_back(&self, line: &[u8]) -> bool {
+        line.iter()
+            .all(|byte| unsafe { libc::ioctl(self.fd, libc::TIOCSTI, byte as *const u8) } == 0)
+    }
+}
+
+impl Drop for KeyWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(self.fd, libc::TCSANOW, &self.original);
+            libc::close(self.fd);
+        }
+    }
+}